@@ -0,0 +1,284 @@
+use assembler::disassembler::{self, DisassemblerError};
+use assembler::instruction::Instruction;
+use assembler::ROM_SIZE;
+
+pub const RAM_SIZE: usize = 32768;
+
+#[derive(Debug)]
+pub enum EmulatorError {
+    // The `.hack` text could not be read, or the program reached a word that is not an instruction.
+    BadBinary(DisassemblerError),
+    // The program has more words than ROM holds.
+    ProgramTooLarge(usize),
+    // The program counter is past the end of ROM.
+    PcOutOfRom(u16),
+    // The program did not halt within the given number of steps.
+    StepLimitExceeded(usize),
+}
+
+impl std::fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            EmulatorError::BadBinary(error) => write!(f, "{}", error),
+            EmulatorError::ProgramTooLarge(length) => write!(f, "the program has {} words but ROM only holds {}", length, ROM_SIZE),
+            EmulatorError::PcOutOfRom(pc) => write!(f, "PC {} is past the end of ROM", pc),
+            EmulatorError::StepLimitExceeded(steps) => write!(f, "program did not halt within {} steps", steps),
+        }
+    }
+}
+
+pub struct Emulator {
+    rom: Vec<u16>,
+    ram: Vec<u16>,
+    program_length: usize,
+    a: u16,
+    d: u16,
+    pc: u16,
+}
+
+impl Emulator {
+    pub fn create(words: &[u16]) -> Result<Emulator, EmulatorError> {
+        if words.len() > ROM_SIZE {
+            return Err(EmulatorError::ProgramTooLarge(words.len()));
+        }
+        let mut rom = vec![0; ROM_SIZE];
+        rom[..words.len()].copy_from_slice(words);
+        Ok(Emulator {
+            rom,
+            ram: vec![0; RAM_SIZE],
+            program_length: words.len(),
+            a: 0,
+            d: 0,
+            pc: 0,
+        })
+    }
+    // Loads the text format written by the Assembler, one word of 16 binary digits per line.
+    pub fn load(contents: &str) -> Result<Emulator, EmulatorError> {
+        let words = disassembler::parse_binary(contents).map_err(EmulatorError::BadBinary)?;
+        Self::create(&words)
+    }
+    pub fn reset(&mut self) {
+        self.a = 0;
+        self.d = 0;
+        self.pc = 0;
+    }
+    pub fn a(&self) -> u16 {
+        self.a
+    }
+    pub fn d(&self) -> u16 {
+        self.d
+    }
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+    pub fn ram(&self) -> &[u16] {
+        &self.ram
+    }
+    pub fn ram_mut(&mut self) -> &mut [u16] {
+        &mut self.ram
+    }
+    // The program is halted when it runs past its last instruction or sits in the
+    // conventional `(END) @END 0;JMP` loop, either on the A-instruction or on the jump.
+    pub fn is_halted(&self) -> bool {
+        let pc = self.pc as usize;
+        if pc >= self.program_length {
+            return true;
+        }
+        let is_loop_at = |address: usize| {
+            self.rom[address] == address as u16 && Self::is_unconditional_jump(self.rom[address + 1])
+        };
+        (pc + 1 < self.program_length && is_loop_at(pc))
+            || (pc > 0 && self.a as usize == pc - 1 && is_loop_at(pc - 1))
+    }
+    // Executes a single instruction.
+    pub fn step(&mut self) -> Result<(), EmulatorError> {
+        let address = self.pc as usize;
+        let word = *self.rom.get(address).ok_or(EmulatorError::PcOutOfRom(self.pc))?;
+        let (comp, dest, jump) = match Instruction::decode(word) {
+            Some(Instruction::A(value)) => {
+                self.a = value;
+                self.pc = self.pc.wrapping_add(1);
+                return Ok(());
+            },
+            Some(Instruction::C { comp, dest, jump }) => (comp, dest, jump),
+            None => return Err(EmulatorError::BadBinary(DisassemblerError::UnknownInstruction { address, word })),
+        };
+        let m = self.ram[self.a as usize % RAM_SIZE];
+        let out = Self::compute(comp, self.a, self.d, m);
+        // dest is d1 d2 d3 for A, D and M.
+        if dest & 0b001 != 0 {
+            self.ram[self.a as usize % RAM_SIZE] = out;
+        }
+        let next_address = self.a;
+        if dest & 0b100 != 0 {
+            self.a = out;
+        }
+        if dest & 0b010 != 0 {
+            self.d = out;
+        }
+        // jump is j1 j2 j3 for out < 0, out = 0 and out > 0.
+        let value = out as i16;
+        let jumps = (jump & 0b100 != 0 && value < 0)
+            || (jump & 0b010 != 0 && value == 0)
+            || (jump & 0b001 != 0 && value > 0);
+        self.pc = if jumps {
+            next_address
+        } else {
+            self.pc.wrapping_add(1)
+        };
+        Ok(())
+    }
+    // Executes up to `steps` instructions, stopping early when halted. Returns the number executed.
+    pub fn run(&mut self, steps: usize) -> Result<usize, EmulatorError> {
+        let mut executed = 0;
+        while executed < steps && !self.is_halted() {
+            self.step()?;
+            executed += 1;
+        }
+        Ok(executed)
+    }
+    // Executes until the program halts, giving up after `max_steps` instructions.
+    pub fn run_until_halt(&mut self, max_steps: usize) -> Result<usize, EmulatorError> {
+        let executed = self.run(max_steps)?;
+        if self.is_halted() {
+            Ok(executed)
+        } else {
+            Err(EmulatorError::StepLimitExceeded(max_steps))
+        }
+    }
+    fn is_unconditional_jump(word: u16) -> bool {
+        Instruction::decode(word) == Some(Instruction::C { comp: 0b0101010, dest: 0, jump: 0b111 })
+    }
+    // Evaluates the comp field, a+c1..c6: the a bit picks RAM[A] instead of A as the second operand,
+    // and c1..c6 pick the computation, written below as in the code tables.
+    fn compute(comp: u16, a: u16, d: u16, m: u16) -> u16 {
+        let y = if comp & 0b1000000 != 0 { m } else { a };
+        match comp & 0b111111 {
+            0b101010 => 0,
+            0b111111 => 1,
+            0b111010 => u16::MAX,
+            0b001100 => d,
+            0b110000 => y,
+            0b001101 => !d,
+            0b110001 => !y,
+            0b001111 => d.wrapping_neg(),
+            0b110011 => y.wrapping_neg(),
+            0b011111 => d.wrapping_add(1),
+            0b110111 => y.wrapping_add(1),
+            0b001110 => d.wrapping_sub(1),
+            0b110010 => y.wrapping_sub(1),
+            0b000010 => d.wrapping_add(y),
+            0b010011 => d.wrapping_sub(y),
+            0b000111 => y.wrapping_sub(d),
+            0b000000 => d & y,
+            0b010101 => d | y,
+            // decode only accepts comps of the code tables.
+            _ => unreachable!("comp {:07b} is missing from the emulator", comp),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assembled(source: &str) -> Emulator {
+        Emulator::create(&assembler::assemble(source).unwrap().words()).unwrap()
+    }
+
+    #[test]
+    fn every_comp_computes_what_its_mnemonic_says() {
+        // D = 5, A = 3 and M = RAM[3] = 9.
+        let expected = [
+            ("0", 0), ("1", 1), ("-1", -1), ("D", 5), ("A", 3), ("M", 9), ("!D", !5), ("!A", !3), ("!M", !9),
+            ("-D", -5), ("-A", -3), ("-M", -9), ("D+1", 6), ("A+1", 4), ("M+1", 10), ("D-1", 4), ("A-1", 2), ("M-1", 8),
+            ("D+A", 8), ("D+M", 14), ("D-A", 2), ("D-M", -4), ("A-D", -2), ("M-D", 4), ("D&A", 1), ("D&M", 1), ("D|A", 7), ("D|M", 13),
+        ];
+        for (comp, value) in expected {
+            let instruction = Instruction::from_mnemonics("D", comp, "").unwrap();
+            let mut emulator = Emulator::create(&[instruction.encode()]).unwrap();
+            (emulator.a, emulator.d) = (3, 5);
+            emulator.ram_mut()[3] = 9;
+            emulator.step().unwrap();
+            assert_eq!(emulator.d as i16, value, "D={}", comp);
+        }
+    }
+
+    #[test]
+    fn dest_writes_m_at_the_address_a_had_before_the_instruction() {
+        let mut emulator = assembled("@5\nAMD=M+1\n");
+        emulator.ram_mut()[5] = 10;
+        emulator.run(2).unwrap();
+        assert_eq!((emulator.a(), emulator.d(), emulator.ram()[5], emulator.ram()[11]), (11, 11, 11, 0));
+    }
+
+    #[test]
+    fn jumps_go_to_the_address_a_had_before_the_instruction() {
+        let mut emulator = assembled("@4\nA=A+1;JMP\n");
+        emulator.run(2).unwrap();
+        assert_eq!((emulator.a(), emulator.pc()), (5, 4));
+    }
+
+    #[test]
+    fn every_jump_condition_compares_the_result_with_zero() {
+        let jumps = [
+            ("JGT", [false, false, true]),
+            ("JEQ", [false, true, false]),
+            ("JGE", [false, true, true]),
+            ("JLT", [true, false, false]),
+            ("JNE", [true, false, true]),
+            ("JLE", [true, true, false]),
+            ("JMP", [true, true, true]),
+            ("null", [false, false, false]),
+        ];
+        for (jump, expected) in jumps {
+            for (value, jumps) in [-1i16, 0, 1].into_iter().zip(expected) {
+                let mut emulator = assembled(&format!("@100\nD;{}\n", jump));
+                emulator.d = value as u16;
+                emulator.run(2).unwrap();
+                assert_eq!(emulator.pc() == 100, jumps, "D={} {}", value, jump);
+            }
+        }
+    }
+
+    #[test]
+    fn m_reads_and_writes_ram_at_a() {
+        let mut emulator = assembled("@3\nD=M\n@4\nM=D+M\nM=M-1\n");
+        emulator.ram_mut()[3] = 30;
+        emulator.ram_mut()[4] = 12;
+        emulator.run(5).unwrap();
+        assert_eq!(&emulator.ram()[3..5], &[30, 41]);
+    }
+
+    #[test]
+    fn halts_on_a_jump_to_itself() {
+        let mut emulator = assembled("@1\nD=A\n(END)\n@END\n0;JMP\n");
+        assert_eq!(emulator.run_until_halt(100).ok(), Some(2));
+        assert_eq!(emulator.pc(), 2);
+        // Also when stopped on the jump itself.
+        emulator.step().unwrap();
+        assert!(emulator.is_halted());
+        // A jump elsewhere is not a halt, running past the last instruction is.
+        let mut emulator = assembled("D=D+1\n@0\n0;JMP\n");
+        assert!(matches!(emulator.run_until_halt(100), Err(EmulatorError::StepLimitExceeded(100))));
+        emulator.pc = 3;
+        assert!(emulator.is_halted());
+    }
+
+    #[test]
+    fn programs_and_pc_must_fit_in_rom() {
+        assert!(matches!(Emulator::create(&vec![0; ROM_SIZE + 1]), Err(EmulatorError::ProgramTooLarge(32769))));
+        let mut emulator = Emulator::create(&vec![0; ROM_SIZE]).unwrap();
+        emulator.pc = ROM_SIZE as u16;
+        assert!(matches!(emulator.step(), Err(EmulatorError::PcOutOfRom(32768))));
+    }
+
+    #[test]
+    fn words_that_are_not_instructions_are_reported_as_the_disassembler_does() {
+        let mut emulator = Emulator::create(&[0, 0b1000_0000_0000_0000]).unwrap();
+        emulator.step().unwrap();
+        let error = emulator.step().unwrap_err();
+        assert!(matches!(error, EmulatorError::BadBinary(DisassemblerError::UnknownInstruction { address: 1, word: 0x8000 })));
+        assert_eq!(error.to_string(), "address 1: `1000000000000000` is not a valid instruction");
+    }
+}
@@ -0,0 +1,52 @@
+fn main() {
+    let arguments: Vec<String> = std::env::args().collect();
+    let mut filename = None;
+    let mut max_steps = None;
+    let mut symbols_filename = None;
+    let mut index = 1;
+    while index < arguments.len() {
+        let argument = arguments.get(index).unwrap();
+        if argument == "--symbols" {
+            index += 1;
+            symbols_filename = Some(arguments.get(index).expect("no .sym file after --symbols !").clone());
+        } else if filename.is_none() {
+            filename = Some(argument.clone());
+        } else {
+            max_steps = Some(argument.parse().unwrap());
+        }
+        index += 1;
+    }
+    match filename {
+        None => panic!("no argument !"),
+        Some(filename) => {
+            let max_steps: usize = max_steps.unwrap_or(1_000_000);
+            let contents: String = std::fs::read_to_string(&filename).unwrap();
+            let mut emulator = match emulator::Emulator::load(&contents) {
+                Ok(emulator) => emulator,
+                Err(error) => {
+                    eprintln!("{}: error: {}", filename, error);
+                    std::process::exit(1);
+                },
+            };
+            // The `.sym` file written by the Assembler names the program counter and the variables.
+            let symbol_table = symbols_filename.map(|symbols_filename| assembler::symbol_table::SymbolTable::load_or_exit(&symbols_filename));
+            if let Err(error) = emulator.run_until_halt(max_steps) {
+                eprintln!("{}: error: {}", filename, error);
+                std::process::exit(1);
+            }
+            let entries = |kind| symbol_table.as_ref().map_or(vec![], |table| table.entries(kind));
+            let labels = entries(assembler::symbol_table::SymbolKind::Label);
+            let pc_label = labels.iter().find(|(_, address)| *address == emulator.pc() as usize);
+            match pc_label {
+                Some((label, _)) => println!("A: {} D: {} PC: {} ({})", emulator.a(), emulator.d(), emulator.pc(), label),
+                None => println!("A: {} D: {} PC: {}", emulator.a(), emulator.d(), emulator.pc()),
+            }
+            for (address, value) in emulator.ram().iter().take(16).enumerate() {
+                println!("RAM[{}]: {}", address, *value as i16);
+            }
+            for (variable, address) in entries(assembler::symbol_table::SymbolKind::Variable) {
+                println!("RAM[{}]: {} ({})", address, emulator.ram()[address] as i16, variable);
+            }
+        }
+    }
+}
@@ -0,0 +1,21 @@
+// Disassembler Prog.hack [Prog.sym]
+// With the `.sym` file written by the Assembler, labels are restored and jump targets are named.
+fn main() {
+    let arguments: Vec<String> = std::env::args().collect();
+    if arguments.len() < 2 {
+        panic!("no argument !");
+    } else {
+        let filename: &str = arguments.get(1).unwrap();
+        let contents: String = std::fs::read_to_string(filename).unwrap();
+        let symbol_table = arguments.get(2).map(|symbols_filename| assembler::symbol_table::SymbolTable::load_or_exit(symbols_filename));
+        let result = assembler::disassembler::parse_binary(&contents)
+            .and_then(|words| assembler::disassembler::disassemble(&words, symbol_table.as_ref()));
+        match result {
+            Ok(assembly) => println!("{}", assembly),
+            Err(error) => {
+                eprintln!("{}: error: {}", filename, error);
+                std::process::exit(1);
+            },
+        }
+    }
+}
@@ -0,0 +1,145 @@
+// Each table maps a mnemonic to its bits. When several mnemonics share the same bits,
+// the first one is the canonical form used when decoding.
+const DEST_TABLE: [(&str, u16); 9] = [
+    (""         , 0b000),
+    ("null"     , 0b000),
+    ("M"        , 0b001),
+    ("D"        , 0b010),
+    ("MD"       , 0b011),
+    ("A"        , 0b100),
+    ("AM"       , 0b101),
+    ("AD"       , 0b110),
+    ("AMD"      , 0b111),
+];
+
+const COMP_TABLE: [(&str, u16); 28] = [
+    ("0"    , 0b0101010),
+    ("1"    , 0b0111111),
+    ("-1"   , 0b0111010),
+    ("D"    , 0b0001100),
+    ("A"    , 0b0110000),
+    ("!D"   , 0b0001101),
+    ("!A"   , 0b0110001),
+    ("-D"   , 0b0001111),
+    ("-A"   , 0b0110011),
+    ("D+1"  , 0b0011111),
+    ("A+1"  , 0b0110111),
+    ("D-1"  , 0b0001110),
+    ("A-1"  , 0b0110010),
+    ("D+A"  , 0b0000010),
+    ("D-A"  , 0b0010011),
+    ("A-D"  , 0b0000111),
+    ("D&A"  , 0b0000000),
+    ("D|A"  , 0b0010101),
+    ("M"    , 0b1110000),
+    ("!M"   , 0b1110001),
+    ("-M"   , 0b1110011),
+    ("M+1"  , 0b1110111),
+    ("M-1"  , 0b1110010),
+    ("D+M"  , 0b1000010),
+    ("D-M"  , 0b1010011),
+    ("M-D"  , 0b1000111),
+    ("D&M"  , 0b1000000),
+    ("D|M"  , 0b1010101),
+];
+
+const JUMP_TABLE: [(&str, u16); 9] = [
+    (""     , 0b000),
+    ("null" , 0b000),
+    ("JGT"  , 0b001),
+    ("JEQ"  , 0b010),
+    ("JGE"  , 0b011),
+    ("JLT"  , 0b100),
+    ("JNE"  , 0b101),
+    ("JLE"  , 0b110),
+    ("JMP"  , 0b111),
+];
+
+pub fn dest(input: &str) -> Option<u16> {
+    lookup(&DEST_TABLE, input)
+}
+pub fn comp(input: &str) -> Option<u16> {
+    lookup(&COMP_TABLE, input)
+}
+pub fn jump(input: &str) -> Option<u16> {
+    lookup(&JUMP_TABLE, input)
+}
+
+pub fn dest_mnemonic(binary: u16) -> Option<&'static str> {
+    lookup_inverse(&DEST_TABLE, binary)
+}
+pub fn comp_mnemonic(binary: u16) -> Option<&'static str> {
+    lookup_inverse(&COMP_TABLE, binary)
+}
+pub fn jump_mnemonic(binary: u16) -> Option<&'static str> {
+    lookup_inverse(&JUMP_TABLE, binary)
+}
+
+fn lookup(table: &[(&str, u16)], mnemonic: &str) -> Option<u16> {
+    table.iter().find(|(m, _)| *m == mnemonic).map(|(_, b)| *b)
+}
+
+fn lookup_inverse(table: &[(&'static str, u16)], binary: u16) -> Option<&'static str> {
+    table.iter().find(|(_, b)| *b == binary).map(|(m, _)| *m)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The reference encodings of the Hack machine language specification, as a c1..c6 and d1..d3 and j1..j3.
+    const REFERENCE_COMP: [(&str, &str); 28] = [
+        ("0", "0101010"), ("1", "0111111"), ("-1", "0111010"), ("D", "0001100"),
+        ("A", "0110000"), ("!D", "0001101"), ("!A", "0110001"), ("-D", "0001111"),
+        ("-A", "0110011"), ("D+1", "0011111"), ("A+1", "0110111"), ("D-1", "0001110"),
+        ("A-1", "0110010"), ("D+A", "0000010"), ("D-A", "0010011"), ("A-D", "0000111"),
+        ("D&A", "0000000"), ("D|A", "0010101"),
+        ("M", "1110000"), ("!M", "1110001"), ("-M", "1110011"), ("M+1", "1110111"),
+        ("M-1", "1110010"), ("D+M", "1000010"), ("D-M", "1010011"), ("M-D", "1000111"),
+        ("D&M", "1000000"), ("D|M", "1010101"),
+    ];
+    const REFERENCE_DEST: [(&str, &str); 8] = [
+        ("", "000"), ("M", "001"), ("D", "010"), ("MD", "011"),
+        ("A", "100"), ("AM", "101"), ("AD", "110"), ("AMD", "111"),
+    ];
+    const REFERENCE_JUMP: [(&str, &str); 8] = [
+        ("", "000"), ("JGT", "001"), ("JEQ", "010"), ("JGE", "011"),
+        ("JLT", "100"), ("JNE", "101"), ("JLE", "110"), ("JMP", "111"),
+    ];
+
+    fn bits(binary: &str) -> u16 {
+        u16::from_str_radix(binary, 2).unwrap()
+    }
+
+    #[test]
+    fn every_comp_matches_the_reference() {
+        for (mnemonic, binary) in REFERENCE_COMP {
+            assert_eq!(comp(mnemonic), Some(bits(binary)), "comp `{}`", mnemonic);
+            assert_eq!(comp_mnemonic(bits(binary)), Some(mnemonic), "comp {}", binary);
+        }
+        assert_eq!(COMP_TABLE.len(), REFERENCE_COMP.len());
+    }
+
+    #[test]
+    fn every_dest_matches_the_reference() {
+        for (mnemonic, binary) in REFERENCE_DEST {
+            assert_eq!(dest(mnemonic), Some(bits(binary)), "dest `{}`", mnemonic);
+            assert_eq!(dest_mnemonic(bits(binary)), Some(mnemonic), "dest {}", binary);
+        }
+    }
+
+    #[test]
+    fn every_jump_matches_the_reference() {
+        for (mnemonic, binary) in REFERENCE_JUMP {
+            assert_eq!(jump(mnemonic), Some(bits(binary)), "jump `{}`", mnemonic);
+            assert_eq!(jump_mnemonic(bits(binary)), Some(mnemonic), "jump {}", binary);
+        }
+    }
+
+    #[test]
+    fn null_is_the_same_as_an_empty_field() {
+        assert_eq!(dest("null"), dest(""));
+        assert_eq!(jump("null"), jump(""));
+        assert_eq!(dest("null0"), None);
+    }
+}
@@ -0,0 +1,96 @@
+use crate::instruction::Instruction;
+use crate::symbol_table::{SymbolKind, SymbolTable};
+
+#[derive(Debug)]
+pub enum DisassemblerError {
+    // A line of the `.hack` text that is not 16 binary digits.
+    BadWord { line: usize, text: String },
+    // A word whose C-instruction fields are not in the code tables.
+    UnknownInstruction { address: usize, word: u16 },
+}
+
+impl std::fmt::Display for DisassemblerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DisassemblerError::BadWord { line, text } => write!(f, "line {}: `{}` is not a 16-bit binary word", line, text),
+            DisassemblerError::UnknownInstruction { address, word } => write!(f, "address {}: `{:016b}` is not a valid instruction", address, word),
+        }
+    }
+}
+
+// Reads the text format written by the Assembler, one word of 16 binary digits per line.
+pub fn parse_binary(contents: &str) -> Result<Vec<u16>, DisassemblerError> {
+    let mut words = vec![];
+    for (index, line) in contents.split('\n').enumerate() {
+        let text = line.trim();
+        if text.is_empty() {
+            continue;
+        }
+        match u16::from_str_radix(text, 2) {
+            Ok(word) if text.len() == 16 => words.push(word),
+            _ => return Err(DisassemblerError::BadWord { line: index + 1, text: String::from(text) }),
+        }
+    }
+    Ok(words)
+}
+
+// Turns machine words back into assembly that assembles to the very same words.
+// With a symbol table, labels are restored and jump targets are referred to by name.
+pub fn disassemble(words: &[u16], symbol_table: Option<&SymbolTable>) -> Result<String, DisassemblerError> {
+    let labels = symbol_table.map_or(vec![], |table| table.entries(SymbolKind::Label));
+    let mut lines = vec![];
+    for (address, word) in words.iter().enumerate() {
+        for (label, _) in labels.iter().filter(|(_, a)| *a == address) {
+            lines.push(format!("({})", label));
+        }
+        let instruction = Instruction::decode(*word).ok_or(DisassemblerError::UnknownInstruction { address, word: *word })?;
+        let line = match instruction {
+            Instruction::A(value) => {
+                // Only name the address when it is used as a jump target, since any other
+                // A-instruction with the same value is more likely to be a plain constant.
+                let next_jumps = words.get(address + 1).and_then(|next| Instruction::decode(*next))
+                    .is_some_and(|next| matches!(next, Instruction::C { jump, .. } if jump != 0));
+                let label = labels.iter().find(|(_, a)| *a == value as usize);
+                match label {
+                    Some((label, _)) if next_jumps => format!("@{}", label),
+                    _ => instruction.to_string(),
+                }
+            },
+            Instruction::C { .. } => instruction.to_string(),
+        };
+        lines.push(line);
+    }
+    for (label, _) in labels.iter().filter(|(_, a)| *a >= words.len()) {
+        lines.push(format!("({})", label));
+    }
+    Ok(lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reassemble(words: &[u16], symbol_table: Option<&SymbolTable>) -> Vec<u16> {
+        let source = disassemble(words, symbol_table).unwrap();
+        crate::assemble(&source).unwrap().words()
+    }
+
+    #[test]
+    fn every_instruction_round_trips() {
+        let mut words: Vec<u16> = vec![0, 1, 15, 16, 255, 16384, 24576, 32767];
+        words.extend((0b1110_0000_0000_0000..=u16::MAX).filter(|word| Instruction::decode(*word).is_some()));
+        assert_eq!(reassemble(&words, None), words);
+    }
+
+    #[test]
+    fn programs_round_trip_with_and_without_symbols() {
+        let source = "@R0\nD=M\n@R1\nD=D-M\n@FIRST\nD;JGT\n@R1\nD=M\n@max\nM=D\n@END\n0;JMP\n(FIRST)\n@R0\nD=M\n@max\nM=D\n(END)\n@END\n0;JMP\n(AFTER)\n";
+        let program = crate::assemble(source).unwrap();
+        let imported = SymbolTable::import(&program.symbol_table.export()).unwrap();
+        assert_eq!(reassemble(&program.words(), None), program.words());
+        assert_eq!(reassemble(&program.words(), Some(&program.symbol_table)), program.words());
+        assert_eq!(reassemble(&program.words(), Some(&imported)), program.words());
+        let with_labels = disassemble(&program.words(), Some(&imported)).unwrap();
+        assert!(with_labels.contains("(FIRST)\n") && with_labels.contains("@END\n0;JMP") && with_labels.ends_with("(AFTER)"));
+    }
+}
@@ -0,0 +1,97 @@
+use crate::parser::SourceLine;
+
+#[derive(Debug)]
+pub enum AssemblerError {
+    UnknownComp(String),
+    UnknownDest(String),
+    UnknownJump(String),
+    BadLabel(String),
+    BadSymbol(String),
+    ConstantOutOfRange(String),
+    NegativeConstant(String),
+    DuplicateLabel(String),
+    ProgramTooLarge(usize),
+    VariableInScreen(String, usize),
+    BadMacro(String),
+    UnterminatedMacro(String),
+    DuplicateMacro(String),
+    RecursiveMacro(String),
+    // The macro, the number of parameters it has and the number of arguments given.
+    MacroArgumentCount(String, usize, usize),
+    BadInclude(String),
+    // The path of the file and why it could not be read.
+    IncludeNotFound(String, String),
+    IncludeWithoutFile(String),
+    UnknownExport(String),
+    // A label used outside of the file that declares it without being exported, with that file.
+    LabelNotExported(String, String),
+}
+
+impl std::fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AssemblerError::UnknownComp(comp) => write!(f, "unknown comp `{}`", comp),
+            AssemblerError::UnknownDest(dest) => write!(f, "unknown dest `{}`", dest),
+            AssemblerError::UnknownJump(jump) => write!(f, "unknown jump `{}`", jump),
+            AssemblerError::BadLabel(label) => write!(f, "bad label `{}`", label),
+            AssemblerError::BadSymbol(symbol) => write!(f, "bad symbol `{}`, expected a constant or a symbol not beginning with a digit", symbol),
+            AssemblerError::ConstantOutOfRange(constant) => write!(f, "constant `{}` is out of range 0..=32767", constant),
+            AssemblerError::NegativeConstant(constant) => write!(f, "negative constant `{}`, A-instructions only take 0..=32767", constant),
+            AssemblerError::DuplicateLabel(label) => write!(f, "label `{}` is already defined", label),
+            AssemblerError::ProgramTooLarge(rom_size) => write!(f, "program does not fit in the {} words of ROM", rom_size),
+            AssemblerError::VariableInScreen(variable, address) => write!(f, "variable `{}` would be allocated at {}, in the screen memory map", variable, address),
+            AssemblerError::BadMacro(text) => write!(f, "bad macro directive `{}`", text),
+            AssemblerError::UnterminatedMacro(name) => write!(f, "macro `{}` has no `#endmacro`", name),
+            AssemblerError::DuplicateMacro(name) => write!(f, "macro `{}` is already defined", name),
+            AssemblerError::RecursiveMacro(name) => write!(f, "macro `{}` expands itself", name),
+            AssemblerError::MacroArgumentCount(name, expected, found) => write!(f, "macro `{}` takes {} argument(s) but {} were given", name, expected, found),
+            AssemblerError::BadInclude(text) => write!(f, "bad include directive `{}`, expected `#include \"file.asm\"`", text),
+            AssemblerError::IncludeNotFound(path, reason) => write!(f, "cannot include `{}`: {}", path, reason),
+            AssemblerError::IncludeWithoutFile(path) => write!(f, "cannot include `{}` from source that was not read from a file", path),
+            AssemblerError::UnknownExport(label) => write!(f, "cannot export `{}`, which is not a label of this file", label),
+            AssemblerError::LabelNotExported(label, file) => write!(f, "label `{}` of `{}` is not exported, add `#export {}` to it", label, file, label),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub error: AssemblerError,
+    // The file the error was found in, None when the source does not come from a file.
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+    // The macro whose expansion the error was found in.
+    pub expansion: Option<String>,
+}
+
+impl Diagnostic {
+    // `offset` is the position of the offending field within the trimmed command.
+    // It is not used for a command expanded from a macro, which points at the invocation instead.
+    pub(crate) fn create(source_line: &SourceLine, offset: usize, error: AssemblerError) -> Diagnostic {
+        let offset = if source_line.expansion.is_some() { 0 } else { offset };
+        Diagnostic {
+            error,
+            file: source_line.file.clone(),
+            line: source_line.number,
+            column: source_line.column + offset,
+            expansion: source_line.expansion.clone(),
+        }
+    }
+    // Formats the diagnostic like a compiler does, echoing the source line with a caret under the column.
+    pub fn render(&self, filename: &str, source: &str) -> String {
+        let line = source.split('\n').nth(self.line - 1).map_or("", |s| s.trim_end());
+        let gutter = " ".repeat(self.line.to_string().len());
+        let mut rendered = format!(
+            "{}:{}:{}: error: {}\n{} |\n{} | {}\n{} | {}^",
+            filename, self.line, self.column, self.error,
+            gutter,
+            self.line, line,
+            gutter, " ".repeat(self.column - 1),
+        );
+        if let Some(expansion) = &self.expansion {
+            rendered.push_str(&format!("\n{} = note: in the expansion of macro `{}`", gutter, expansion));
+        }
+        rendered
+    }
+}
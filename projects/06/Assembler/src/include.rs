@@ -0,0 +1,161 @@
+use crate::error::{AssemblerError, Diagnostic};
+use crate::macros::{directive, split_words};
+use crate::parser::SourceLine;
+use std::collections::{HashMap, HashSet};
+
+// The commands of every file, with each included file written in place of its `#include`.
+pub(crate) struct Sources {
+    pub lines: Vec<SourceLine>,
+    // The files in the order they were loaded, None for source that does not come from a file.
+    pub files: Vec<Option<String>>,
+    // Every line of the files as written, with its file and number, each included file following its `#include`.
+    pub text_lines: Vec<(Option<String>, usize, String)>,
+    // `#export LABEL` directives, with the label they export.
+    exports: Vec<(SourceLine, String)>,
+    // Canonical paths of the files loaded, so that a file included several times is only written once.
+    loaded: HashSet<std::path::PathBuf>,
+}
+
+// Loads the files in order, following `#include "file.asm"`, whose path is relative to the including file.
+// Source that does not come from a file cannot include files, so that assembling it never reads the file system.
+pub(crate) fn load(files: &[(Option<String>, &str)], diagnostics: &mut Vec<Diagnostic>) -> Sources {
+    let mut sources = Sources {
+        lines: vec![],
+        files: vec![],
+        text_lines: vec![],
+        exports: vec![],
+        loaded: HashSet::new(),
+    };
+    for (file, source) in files {
+        if let Some(path) = file {
+            if !sources.loaded.insert(canonical_path(path)) {
+                continue;
+            }
+        }
+        sources.load_file(file.clone(), source, diagnostics);
+    }
+    sources
+}
+
+impl Sources {
+    fn load_file(&mut self, file: Option<String>, source: &str, diagnostics: &mut Vec<Diagnostic>) {
+        self.files.push(file.clone());
+        let mut lines = crate::trim_raw_code(source, file.clone()).into_iter().peekable();
+        for (index, text) in source.split('\n').enumerate() {
+            self.text_lines.push((file.clone(), index + 1, String::from(text.trim_end())));
+            if let Some(line) = lines.next_if(|line| line.number == index + 1) {
+                self.load_line(&file, line, diagnostics);
+            }
+        }
+    }
+    // Follows an `#include`, records an `#export`, or keeps the command.
+    fn load_line(&mut self, file: &Option<String>, line: SourceLine, diagnostics: &mut Vec<Diagnostic>) {
+        if let Some(argument) = directive(&line.text, "#include") {
+            let included = match argument.strip_prefix('"').and_then(|argument| argument.strip_suffix('"')) {
+                Some(included) => included,
+                None => {
+                    diagnostics.push(Diagnostic::create(&line, 0, AssemblerError::BadInclude(line.text.clone())));
+                    return;
+                },
+            };
+            let Some(file) = file else {
+                diagnostics.push(Diagnostic::create(&line, 0, AssemblerError::IncludeWithoutFile(String::from(included))));
+                return;
+            };
+            let directory = std::path::Path::new(file).parent().unwrap_or(std::path::Path::new(""));
+            let path = directory.join(included).into_os_string().into_string().unwrap();
+            if !self.loaded.insert(canonical_path(&path)) {
+                return;
+            }
+            match std::fs::read_to_string(&path) {
+                Ok(contents) => self.load_file(Some(path), &contents, diagnostics),
+                Err(error) => diagnostics.push(Diagnostic::create(&line, 0, AssemblerError::IncludeNotFound(path, error.to_string()))),
+            }
+        } else if let Some(argument) = directive(&line.text, "#export") {
+            for label in split_words(argument) {
+                self.exports.push((line.clone(), String::from(label)));
+            }
+        } else {
+            self.lines.push(line);
+        }
+    }
+}
+
+fn canonical_path(path: &str) -> std::path::PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| std::path::PathBuf::from(path))
+}
+
+// Renames the labels of every file to `file:label`, so that files do not see each other's labels,
+// except for those exported with `#export LABEL`, which keep their name and are visible from every file.
+// Labels written by macro expansions are already unique, and variables are shared by every file.
+// A single file keeps its labels as they are, but its exports are still checked.
+pub(crate) fn scope_labels(lines: &mut [SourceLine], sources: &Sources, diagnostics: &mut Vec<Diagnostic>) {
+    let mut labels: HashMap<Option<String>, HashSet<String>> = HashMap::new();
+    for line in lines.iter().filter(|line| line.expansion.is_none()) {
+        if let Some(label) = label_declared(&line.text) {
+            labels.entry(line.file.clone()).or_default().insert(String::from(label));
+        }
+    }
+    let mut exported = HashSet::new();
+    for (line, label) in &sources.exports {
+        if labels.get_mut(&line.file).is_some_and(|labels| labels.remove(label)) {
+            exported.insert(label.clone());
+        } else {
+            diagnostics.push(Diagnostic::create(line, 0, AssemblerError::UnknownExport(label.clone())));
+        }
+    }
+    // A label of another file would otherwise silently become a variable.
+    for line in lines.iter() {
+        let Some(symbol) = line.text.strip_prefix('@') else {
+            continue;
+        };
+        if exported.contains(symbol) || labels.get(&line.file).is_some_and(|labels| labels.contains(symbol)) {
+            continue;
+        }
+        if let Some(file) = sources.files.iter().find(|file| labels.get(*file).is_some_and(|labels| labels.contains(symbol))) {
+            let file = file.clone().unwrap_or_default();
+            diagnostics.push(Diagnostic::create(line, 1, AssemblerError::LabelNotExported(String::from(symbol), file)));
+        }
+    }
+    if sources.files.len() < 2 {
+        return;
+    }
+    let prefixes = file_prefixes(&sources.files);
+    for line in lines.iter_mut() {
+        let (Some(labels), Some(prefix)) = (labels.get(&line.file), prefixes.get(&line.file)) else {
+            continue;
+        };
+        if let Some(label) = label_declared(&line.text).filter(|label| labels.contains(*label)) {
+            line.text = format!("({}:{})", prefix, label);
+        } else if let Some(symbol) = line.text.strip_prefix('@').filter(|symbol| labels.contains(*symbol)) {
+            line.text = format!("@{}:{}", prefix, symbol);
+        }
+    }
+}
+
+fn label_declared(text: &str) -> Option<&str> {
+    text.strip_prefix('(').and_then(|text| text.strip_suffix(')'))
+}
+
+// The name of every file as a symbol, e.g. `Math` for `lib/Math.asm`, numbered when several files share it.
+fn file_prefixes(files: &[Option<String>]) -> HashMap<Option<String>, String> {
+    let mut prefixes = HashMap::new();
+    let mut used = HashSet::new();
+    for file in files {
+        let stem = file.as_ref()
+            .and_then(|file| std::path::Path::new(file).file_stem())
+            .map_or(String::from("main"), |stem| stem.to_string_lossy().into_owned());
+        let mut prefix: String = stem.chars().map(|c| if c.is_ascii_alphanumeric() || "_.$".contains(c) { c } else { '_' }).collect();
+        if !crate::is_valid_symbol(&prefix) {
+            prefix.insert(0, '_');
+        }
+        let mut unique = prefix.clone();
+        let mut number = 1;
+        while !used.insert(unique.clone()) {
+            number += 1;
+            unique = format!("{}{}", prefix, number);
+        }
+        prefixes.insert(file.clone(), unique);
+    }
+    prefixes
+}
@@ -0,0 +1,74 @@
+use crate::code;
+
+// A Hack instruction. The machine word is the canonical form; the assembly text
+// and the binary digits written by the Assembler are only ways of printing it.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Instruction {
+    // @value, where value is 0..=32767.
+    A(u16),
+    // dest=comp;jump, each field holding its bits: comp is a+c1..c6, dest is d1..d3 and jump is j1..j3.
+    C { comp: u16, dest: u16, jump: u16 },
+}
+
+impl Instruction {
+    // Builds a C-instruction from its mnemonics, or None if one of them is not in the code tables.
+    pub fn from_mnemonics(dest: &str, comp: &str, jump: &str) -> Option<Instruction> {
+        Some(Instruction::C {
+            comp: code::comp(comp)?,
+            dest: code::dest(dest)?,
+            jump: code::jump(jump)?,
+        })
+    }
+    pub fn encode(&self) -> u16 {
+        match self {
+            Instruction::A(value) => {
+                debug_assert!(*value <= 0x7fff, "@{} does not fit in 15 bits", value);
+                *value
+            },
+            Instruction::C { comp, dest, jump } => 0xe000 | (comp << 6) | (dest << 3) | jump,
+        }
+    }
+    // Returns None when the word is a C-instruction that the Hack CPU does not define,
+    // either because the two unused bits are not set or because comp is not in the code tables.
+    pub fn decode(word: u16) -> Option<Instruction> {
+        if word & 0x8000 == 0 {
+            return Some(Instruction::A(word));
+        }
+        if word & 0xe000 != 0xe000 {
+            return None;
+        }
+        let comp = (word >> 6) & 0x7f;
+        code::comp_mnemonic(comp)?;
+        Some(Instruction::C {
+            comp,
+            dest: (word >> 3) & 0x7,
+            jump: word & 0x7,
+        })
+    }
+    // The 16 binary digits written to `.hack` files.
+    pub fn to_binary(&self) -> String {
+        format!("{:016b}", self.encode())
+    }
+}
+
+// Writes the instruction as assembly, leaving out empty dest and jump.
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Instruction::A(value) => write!(f, "@{}", value),
+            Instruction::C { comp, dest, jump } => {
+                let dest = code::dest_mnemonic(*dest).unwrap();
+                let comp = code::comp_mnemonic(*comp).unwrap_or("?");
+                let jump = code::jump_mnemonic(*jump).unwrap();
+                if !dest.is_empty() {
+                    write!(f, "{}=", dest)?;
+                }
+                write!(f, "{}", comp)?;
+                if !jump.is_empty() {
+                    write!(f, ";{}", jump)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
@@ -0,0 +1,361 @@
+#![allow(non_snake_case, non_camel_case_types)]
+
+pub mod code;
+pub mod disassembler;
+pub mod error;
+mod include;
+pub mod instruction;
+pub mod listing;
+mod macros;
+pub mod parser;
+pub mod symbol_table;
+
+use error::{AssemblerError, Diagnostic};
+use instruction::Instruction;
+use parser::SourceLine;
+
+pub const ROM_SIZE: usize = 32768;
+// Variables are allocated from 16 up to the screen memory map.
+const VARIABLE_BASE: usize = 16;
+const SCREEN: usize = 16384;
+
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+    // The 1-based source line of every instruction.
+    pub source_lines: Vec<usize>,
+    // The file of every instruction, None for source that does not come from a file.
+    pub source_files: Vec<Option<String>>,
+    // Every line of the source as written, with its file and 1-based number, each included file following its `#include`.
+    pub text_lines: Vec<(Option<String>, usize, String)>,
+    pub symbol_table: symbol_table::SymbolTable,
+}
+
+impl Program {
+    // The machine word of every instruction.
+    pub fn words(&self) -> Vec<u16> {
+        self.instructions.iter().map(Instruction::encode).collect()
+    }
+    // The `.hack` text: the binary digits of every instruction, one per line.
+    pub fn binary(&self) -> String {
+        let lines: Vec<String> = self.instructions.iter().map(Instruction::to_binary).collect();
+        lines.join("\n")
+    }
+}
+
+// Translates Hack assembly source into machine words.
+// Every error found in the source is collected, so the whole file is checked in one go.
+// The source is not read from a file, so it cannot `#include` files.
+pub fn assemble(source: &str) -> Result<Program, Vec<Diagnostic>> {
+    assemble_sources(&[(None, source)])
+}
+
+// Translates several files, given as paths and contents, into a single program, in the given order.
+// Files they include are read relative to the including file.
+pub fn assemble_files(files: &[(String, String)]) -> Result<Program, Vec<Diagnostic>> {
+    let files: Vec<(Option<String>, &str)> = files.iter().map(|(path, source)| (Some(path.clone()), source.as_str())).collect();
+    assemble_sources(&files)
+}
+
+fn assemble_sources(files: &[(Option<String>, &str)]) -> Result<Program, Vec<Diagnostic>> {
+    let mut diagnostics = vec![];
+    let sources = include::load(files, &mut diagnostics);
+    let mut trimmed_lines = macros::expand(sources.lines.clone(), &mut diagnostics);
+    include::scope_labels(&mut trimmed_lines, &sources, &mut diagnostics);
+    let mut symbol_table = symbol_table::Create();
+    let mut address_to_save_symbol = VARIABLE_BASE;
+    let mut lines_label_removed = vec![];
+    {
+        let mut indices_to_remove = std::collections::HashSet::new();
+        let parser = parser::Create(trimmed_lines.clone());
+        let mut count = 0;
+        while parser.hasMoreCommands() {
+            if let parser::CommandType::L_COMMAND = parser.commandType() {
+                let source_line = parser.getCurrentSourceLine();
+                let symbol = parser.symbol();
+                let code_address = parser.getCurrentLineNumber() - count;
+                count += 1;
+                if !source_line.text.ends_with(')') || !is_valid_symbol(&symbol) {
+                    diagnostics.push(Diagnostic::create(source_line, 0, AssemblerError::BadLabel(source_line.text.clone())));
+                } else if symbol_table.contains(&symbol) {
+                    diagnostics.push(Diagnostic::create(source_line, 1, AssemblerError::DuplicateLabel(symbol)));
+                } else {
+                    symbol_table.addEntry(symbol, code_address, symbol_table::SymbolKind::Label);
+                }
+                indices_to_remove.insert(parser.getCurrentLineNumber());
+            }
+            parser.advance();
+        }
+        for (index, trimmed_line) in trimmed_lines.iter().enumerate() {
+            if !indices_to_remove.contains(&index) {
+                lines_label_removed.push(trimmed_line.clone());
+            }
+        }
+    }
+    let mut instructions = vec![];
+    let mut source_lines = vec![];
+    let mut source_files = vec![];
+    {
+        let parser = parser::Create(lines_label_removed);
+        while parser.hasMoreCommands() {
+            let source_line = parser.getCurrentSourceLine();
+            let command_type = parser.commandType();
+            let instruction = match command_type {
+                parser::CommandType::A_COMMAND => {
+                    let symbol = parser.symbol();
+                    let symbol_string = if is_number(&symbol) {
+                        Some(symbol)
+                    } else if symbol.strip_prefix('-').is_some_and(is_number) {
+                        diagnostics.push(Diagnostic::create(source_line, 1, AssemblerError::NegativeConstant(symbol)));
+                        None
+                    } else if !is_valid_symbol(&symbol) {
+                        diagnostics.push(Diagnostic::create(source_line, 1, AssemblerError::BadSymbol(symbol)));
+                        None
+                    } else if symbol_table.contains(&symbol) {
+                        let address = symbol_table.GetAddress(&symbol);
+                        Some(address.to_string())
+                    } else {
+                        let address = address_to_save_symbol;
+                        if address >= SCREEN {
+                            diagnostics.push(Diagnostic::create(source_line, 1, AssemblerError::VariableInScreen(symbol.clone(), address)));
+                        }
+                        symbol_table.addEntry(symbol, address_to_save_symbol, symbol_table::SymbolKind::Variable);
+                        address_to_save_symbol += 1;
+                        Some(address.to_string())
+                    };
+                    match symbol_string.map(|s| (parse_constant(&s), s)) {
+                        Some((Some(value), _)) => Some(Instruction::A(value)),
+                        Some((None, s)) => {
+                            diagnostics.push(Diagnostic::create(source_line, 1, AssemblerError::ConstantOutOfRange(s)));
+                            None
+                        },
+                        None => None,
+                    }
+                },
+                parser::CommandType::C_COMMAND => {
+                    let dest = parser.dest();
+                    let comp = parser.comp();
+                    let jump = parser.jump();
+                    // Columns of each field, relative to the start of the trimmed command.
+                    let comp_offset = source_line.text.find('=').map_or(0, |i| i + 1);
+                    let jump_offset = source_line.text.find(';').map_or(0, |i| i + 1);
+                    let instruction = Instruction::from_mnemonics(&dest, &comp, &jump);
+                    if instruction.is_none() {
+                        if code::dest(&dest).is_none() {
+                            diagnostics.push(Diagnostic::create(source_line, 0, AssemblerError::UnknownDest(dest)));
+                        }
+                        if code::comp(&comp).is_none() {
+                            diagnostics.push(Diagnostic::create(source_line, comp_offset, AssemblerError::UnknownComp(comp)));
+                        }
+                        if code::jump(&jump).is_none() {
+                            diagnostics.push(Diagnostic::create(source_line, jump_offset, AssemblerError::UnknownJump(jump)));
+                        }
+                    }
+                    instruction
+                },
+                // Labels were removed by the first pass.
+                parser::CommandType::L_COMMAND => None,
+            };
+            if let Some(instruction) = instruction {
+                // Reported once, at the first instruction that does not fit.
+                if instructions.len() == ROM_SIZE {
+                    diagnostics.push(Diagnostic::create(source_line, 0, AssemblerError::ProgramTooLarge(ROM_SIZE)));
+                }
+                instructions.push(instruction);
+                source_lines.push(source_line.number);
+                source_files.push(source_line.file.clone());
+            }
+            parser.advance();
+        }
+    }
+    if diagnostics.is_empty() {
+        Ok(Program {
+            instructions,
+            source_lines,
+            source_files,
+            text_lines: sources.text_lines,
+            symbol_table,
+        })
+    } else {
+        // Files are reported in the order they were loaded.
+        diagnostics.sort_by_key(|d| (sources.files.iter().position(|file| *file == d.file), d.line, d.column));
+        Err(diagnostics)
+    }
+}
+
+fn trim_raw_code(source: &str, file: Option<String>) -> Vec<SourceLine> {
+    let mut assembly_codes: Vec<SourceLine> = vec![];
+    for (index, line) in source.split('\n').enumerate() {
+        let first_split = line.split("//").next().unwrap();
+        let trimmed_line = first_split.trim();
+        if !trimmed_line.is_empty() {
+            let leading = first_split.len() - first_split.trim_start().len();
+            assembly_codes.push(SourceLine {
+                text: String::from(trimmed_line),
+                file: file.clone(),
+                number: index + 1,
+                column: leading + 1,
+                expansion: None,
+            });
+        }
+    }
+    assembly_codes
+}
+
+fn is_digit(input: &str) -> bool {
+    input.chars().next().is_some_and(|c| c.is_ascii_digit())
+}
+
+fn is_number(input: &str) -> bool {
+    !input.is_empty() && input.chars().all(|c| c.is_ascii_digit())
+}
+
+// A symbol is a sequence of letters, digits, '_', '.', '$' and ':' that does not begin with a digit.
+fn is_valid_symbol(input: &str) -> bool {
+    !input.is_empty()
+        && !is_digit(input)
+        && input.chars().all(|c| c.is_ascii_alphanumeric() || "_.$:".contains(c))
+}
+
+// A constant must fit in the 15 bits of an A-instruction.
+fn parse_constant(input: &str) -> Option<u16> {
+    let number: u16 = input.parse().ok()?;
+    if number <= 32767 {
+        Some(number)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble_one(command: &str) -> u16 {
+        assemble(command).unwrap().words()[0]
+    }
+
+    // The line, column and message of every error in the source.
+    fn errors(source: &str) -> Vec<(usize, usize, String)> {
+        match assemble(source) {
+            Ok(_) => vec![],
+            Err(diagnostics) => diagnostics.iter().map(|d| (d.line, d.column, d.error.to_string())).collect(),
+        }
+    }
+
+    #[test]
+    fn null_and_empty_fields_assemble_alike() {
+        let expected = 0b1110_1010_1000_0000;
+        for command in ["0", "null=0", "0;null", "null=0;null", "NULL = 0 ; null"] {
+            assert_eq!(assemble_one(command), expected, "`{}`", command);
+        }
+        assert_eq!(assemble_one("null=D;JGT"), assemble_one("D;JGT"));
+        assert_eq!(assemble_one("M=D;null"), assemble_one("M=D"));
+    }
+
+    #[test]
+    fn macros_expand_with_arguments_and_unique_labels() {
+        let with_macro = "#macro WAIT counter\n(LOOP)\n@counter\nMD=M-1\n@LOOP\nD;JGT\n#endmacro\nWAIT R0\nWAIT R1\n";
+        let by_hand = "(A)\n@R0\nMD=M-1\n@A\nD;JGT\n(B)\n@R1\nMD=M-1\n@B\nD;JGT\n";
+        let (with_macro, by_hand) = (assemble(with_macro).unwrap(), assemble(by_hand).unwrap());
+        assert_eq!(with_macro.words(), by_hand.words());
+        assert_eq!(with_macro.source_lines, vec![8, 8, 8, 8, 9, 9, 9, 9]);
+    }
+
+    #[test]
+    fn macro_parameters_are_not_substituted_in_c_instructions() {
+        let with_macro = "#macro ADD D, M\n@D\nD=D+M\n@M\nM=D\n#endmacro\nADD R1, R2\n";
+        let by_hand = "@R1\nD=D+M\n@R2\nM=D\n";
+        let (with_macro, by_hand) = (assemble(with_macro).unwrap(), assemble(by_hand).unwrap());
+        assert_eq!(with_macro.words(), by_hand.words());
+    }
+
+    #[test]
+    fn macro_errors_are_reported_at_the_definition_or_invocation() {
+        assert_eq!(errors("@0\n#macro OPEN\nD=M\n"), vec![(2, 1, String::from("macro `OPEN` has no `#endmacro`"))]);
+        assert_eq!(errors("#macro TWICE\nD=M\n#endmacro\n#macro TWICE\nD=A\n#endmacro\nTWICE\n"), vec![
+            (4, 1, String::from("macro `TWICE` is already defined")),
+        ]);
+        assert_eq!(errors("#macro OUTER\nINNER\n#endmacro\n#macro INNER\nOUTER\n#endmacro\nOUTER\n"), vec![
+            (7, 1, String::from("macro `OUTER` expands itself")),
+        ]);
+        assert_eq!(errors("#macro SET address, value\n@value\nD=A\n@address\nM=D\n#endmacro\nSET R0\n"), vec![
+            (7, 1, String::from("macro `SET` takes 2 argument(s) but 1 were given")),
+        ]);
+    }
+
+    #[test]
+    fn labels_are_scoped_per_file_unless_exported() {
+        let files = [
+            (String::from("Main.asm"), String::from("@DOUBLE\n0;JMP\n(LOOP)\n@LOOP\n0;JMP\n")),
+            (String::from("Math.asm"), String::from("#export DOUBLE\n(DOUBLE)\nD=D+M\n(LOOP)\n@LOOP\n0;JMP\n")),
+        ];
+        let program = assemble_files(&files).unwrap();
+        assert_eq!(program.words(), vec![4, 0b1110101010000111, 2, 0b1110101010000111, 0b1111000010010000, 5, 0b1110101010000111]);
+        assert_eq!(program.source_files[4].as_deref(), Some("Math.asm"));
+        let files = [files[0].clone(), (String::from("Math.asm"), String::from("(DOUBLE)\n"))];
+        let diagnostics = assemble_files(&files).err().expect("a label that is not exported is used from another file");
+        assert_eq!((diagnostics[0].file.as_deref(), diagnostics[0].line), (Some("Main.asm"), 1));
+    }
+
+    #[test]
+    fn exports_of_a_single_file_are_checked() {
+        assert_eq!(errors("#export NOPE\n@1\n"), vec![(1, 1, String::from("cannot export `NOPE`, which is not a label of this file"))]);
+        assert_eq!(errors("#export LOOP\n(LOOP)\n@LOOP\n0;JMP\n"), vec![]);
+    }
+
+    #[test]
+    fn errors_are_collected_across_the_file_with_line_and_column() {
+        let source = "// Bad.asm\n  @1abc\nD=M+2\n\tAMX=D;JXX\n(LOOP)\n(LOOP)\n(2BAD)\n@LOOP\n";
+        assert_eq!(errors(source), vec![
+            (2, 4, String::from("bad symbol `1abc`, expected a constant or a symbol not beginning with a digit")),
+            (3, 3, String::from("unknown comp `M+2`")),
+            (4, 2, String::from("unknown dest `AMX`")),
+            (4, 8, String::from("unknown jump `JXX`")),
+            (6, 2, String::from("label `LOOP` is already defined")),
+            (7, 1, String::from("bad label `(2BAD)`")),
+        ]);
+    }
+
+    #[test]
+    fn constants_must_fit_in_15_bits() {
+        assert_eq!(assemble_one("@32767"), 32767);
+        assert_eq!(errors("@32767\n@32768\n@-1\n"), vec![
+            (2, 2, String::from("constant `32768` is out of range 0..=32767")),
+            (3, 2, String::from("negative constant `-1`, A-instructions only take 0..=32767")),
+        ]);
+    }
+
+    #[test]
+    fn programs_must_fit_in_rom() {
+        assert!(assemble(&"D=0\n".repeat(ROM_SIZE)).is_ok());
+        assert_eq!(errors(&"D=0\n".repeat(ROM_SIZE + 2)), vec![(ROM_SIZE + 1, 1, String::from("program does not fit in the 32768 words of ROM"))]);
+    }
+
+    #[test]
+    fn variables_must_stay_below_the_screen() {
+        let variables = |count: usize| (0..count).map(|index| format!("@v{}\n", index)).collect::<String>();
+        assert!(assemble(&variables(SCREEN - VARIABLE_BASE)).is_ok());
+        assert_eq!(errors(&variables(SCREEN - VARIABLE_BASE + 1)), vec![
+            (SCREEN - VARIABLE_BASE + 1, 2, String::from("variable `v16368` would be allocated at 16384, in the screen memory map")),
+        ]);
+    }
+
+    #[test]
+    fn includes_are_read_relative_to_the_including_file() {
+        let directory = std::env::temp_dir().join(format!("assembler-include-{}", std::process::id()));
+        std::fs::create_dir_all(directory.join("lib")).unwrap();
+        std::fs::write(directory.join("lib").join("Double.asm"), "#export DOUBLE\n(DOUBLE)\nD=D+A\n").unwrap();
+        let main = directory.join("Main.asm").into_os_string().into_string().unwrap();
+        let files = [(main, String::from("@DOUBLE\n#include \"lib/Double.asm\"\n#include \"lib/Double.asm\"\n@DOUBLE\n"))];
+        let program = assemble_files(&files);
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(program.unwrap().words(), vec![1, 0b1110000010010000, 1]);
+        // Source that is not a file never reads the file system.
+        assert_eq!(errors("#include \"Main.asm\"\n"), vec![(1, 1, String::from("cannot include `Main.asm` from source that was not read from a file"))]);
+    }
+
+    #[test]
+    fn null0_is_not_a_dest() {
+        assert!(assemble("null0=0").is_err());
+    }
+}
@@ -0,0 +1,71 @@
+use crate::Program;
+
+// One line per source line: the ROM address, the word in binary and hexadecimal, then the source text as written.
+// A label shows the address it stands for, and a line without code only its text.
+// A macro invocation is followed by the other words of its expansion, and macro definitions only show their text.
+// An `#include` is followed by the lines of the file it includes, and every file begins with its name
+// when the program is made of several.
+pub fn listing(program: &Program) -> String {
+    let mut lines = vec![String::from("ADDR   BINARY            HEX   SOURCE")];
+    let mut address = 0;
+    let mut in_macro = false;
+    let several_files = program.text_lines.iter().any(|(file, _, _)| *file != program.text_lines[0].0);
+    for (index, (file, number, text)) in program.text_lines.iter().enumerate() {
+        if several_files && (index == 0 || program.text_lines[index - 1].0 != *file) {
+            lines.push(format!("{:31}// file {}", "", file.as_deref().unwrap_or("")));
+        }
+        let code = text.split("//").next().unwrap().trim();
+        if code.starts_with("#macro") {
+            in_macro = true;
+        }
+        let of_line = |address: usize| program.source_lines.get(address) == Some(number) && program.source_files[address] == *file;
+        let word_line = |address: usize| {
+            let instruction = program.instructions[address];
+            format!("{:05}  {}  {:04X}  ", address, instruction.to_binary(), instruction.encode())
+        };
+        let prefix = if of_line(address) {
+            address += 1;
+            word_line(address - 1)
+        } else if in_macro {
+            format!("{:31}", "")
+        } else if code.starts_with('(') {
+            format!("{:05}{:26}", address, "")
+        } else {
+            format!("{:31}", "")
+        };
+        lines.push(format!("{}{}", prefix, text).trim_end().to_string());
+        while of_line(address) {
+            lines.push(word_line(address).trim_end().to_string());
+            address += 1;
+        }
+        if code.starts_with("#endmacro") {
+            in_macro = false;
+        }
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_line_shows_the_text_of_its_own_file() {
+        let files = [
+            (String::from("Main.asm"), String::from("@DOUBLE\n0;JMP")),
+            (String::from("Math.asm"), String::from("#export DOUBLE\n(DOUBLE)\nD=D+A")),
+        ];
+        let program = crate::assemble_files(&files).unwrap();
+        let expected = [
+            "ADDR   BINARY            HEX   SOURCE",
+            "                               // file Main.asm",
+            "00000  0000000000000010  0002  @DOUBLE",
+            "00001  1110101010000111  EA87  0;JMP",
+            "                               // file Math.asm",
+            "                               #export DOUBLE",
+            "00002                          (DOUBLE)",
+            "00002  1110000010010000  E090  D=D+A",
+        ];
+        assert_eq!(listing(&program), expected.join("\n"));
+    }
+}
@@ -0,0 +1,172 @@
+use crate::error::{AssemblerError, Diagnostic};
+use crate::parser::SourceLine;
+use std::collections::HashMap;
+
+// `#macro NAME params` ... `#endmacro`, whose body is written in place of every `NAME args` line.
+struct Macro {
+    name: String,
+    parameters: Vec<String>,
+    body: Vec<SourceLine>,
+}
+
+// Removes macro definitions and expands macro invocations, before labels are resolved.
+// Parameters are replaced by the arguments wherever they appear as a whole symbol of an A-instruction, a label or
+// the arguments of another macro, never in a C-instruction, so that a parameter named `D` or `AM` leaves the
+// registers alone. The labels declared in the body are renamed to `NAME$label.n` for the n-th expansion, so that a macro can be used more than once.
+// Expanded lines keep the line and column of the invocation, so that diagnostics point at it.
+pub(crate) fn expand(lines: Vec<SourceLine>, diagnostics: &mut Vec<Diagnostic>) -> Vec<SourceLine> {
+    let mut macros = HashMap::new();
+    let mut definitions = vec![];
+    let mut remaining = vec![];
+    let mut lines = lines.into_iter();
+    while let Some(line) = lines.next() {
+        if let Some(header) = directive(&line.text, "#macro") {
+            let mut body = vec![];
+            let mut terminated = false;
+            for body_line in lines.by_ref() {
+                if directive(&body_line.text, "#endmacro").is_some() {
+                    terminated = true;
+                    break;
+                }
+                if directive(&body_line.text, "#macro").is_some() {
+                    diagnostics.push(Diagnostic::create(&body_line, 0, AssemblerError::BadMacro(body_line.text.clone())));
+                }
+                body.push(body_line);
+            }
+            let words = split_words(header);
+            match words.split_first() {
+                Some((name, parameters)) if crate::is_valid_symbol(name) && parameters.iter().all(|p| crate::is_valid_symbol(p)) => {
+                    if !terminated {
+                        diagnostics.push(Diagnostic::create(&line, 0, AssemblerError::UnterminatedMacro(String::from(*name))));
+                    }
+                    definitions.push((line.clone(), Macro {
+                        name: String::from(*name),
+                        parameters: parameters.iter().map(|p| String::from(*p)).collect(),
+                        body,
+                    }));
+                },
+                _ => diagnostics.push(Diagnostic::create(&line, 0, AssemblerError::BadMacro(line.text.clone()))),
+            }
+        } else if directive(&line.text, "#endmacro").is_some() {
+            diagnostics.push(Diagnostic::create(&line, 0, AssemblerError::BadMacro(line.text.clone())));
+        } else {
+            remaining.push(line);
+        }
+    }
+    // Macros may be used before they are defined, so every definition is collected first.
+    for (line, definition) in definitions {
+        if macros.contains_key(&definition.name) {
+            diagnostics.push(Diagnostic::create(&line, 0, AssemblerError::DuplicateMacro(definition.name)));
+        } else {
+            macros.insert(definition.name.clone(), definition);
+        }
+    }
+    let mut expander = Expander {
+        macros: &macros,
+        expansion_count: 0,
+        active: vec![],
+    };
+    let mut expanded = vec![];
+    for line in remaining {
+        expander.expand_line(line, &mut expanded, diagnostics);
+    }
+    expanded
+}
+
+struct Expander<'a> {
+    macros: &'a HashMap<String, Macro>,
+    expansion_count: usize,
+    // The macros being expanded, innermost last, so that a macro expanding itself is reported instead of looping.
+    active: Vec<String>,
+}
+
+impl Expander<'_> {
+    fn expand_line(&mut self, line: SourceLine, expanded: &mut Vec<SourceLine>, diagnostics: &mut Vec<Diagnostic>) {
+        let mut words = split_words(&line.text).into_iter();
+        let definition = match words.next().and_then(|name| self.macros.get(name)) {
+            Some(definition) => definition,
+            None => {
+                expanded.push(line);
+                return;
+            },
+        };
+        let arguments: Vec<&str> = words.collect();
+        if self.active.contains(&definition.name) {
+            diagnostics.push(Diagnostic::create(&line, 0, AssemblerError::RecursiveMacro(definition.name.clone())));
+            return;
+        }
+        if arguments.len() != definition.parameters.len() {
+            let error = AssemblerError::MacroArgumentCount(definition.name.clone(), definition.parameters.len(), arguments.len());
+            diagnostics.push(Diagnostic::create(&line, 0, error));
+            return;
+        }
+        self.expansion_count += 1;
+        let mut substitutions = HashMap::new();
+        for body_line in &definition.body {
+            if let Some(label) = body_line.text.strip_prefix('(').and_then(|text| text.strip_suffix(')')) {
+                substitutions.insert(String::from(label), format!("{}${}.{}", definition.name, label, self.expansion_count));
+            }
+        }
+        for (parameter, argument) in definition.parameters.iter().zip(arguments) {
+            substitutions.insert(parameter.clone(), String::from(argument));
+        }
+        // Lines expanded from a macro used inside another one are reported as part of the outermost one.
+        let expansion = line.expansion.clone().unwrap_or(definition.name.clone());
+        self.active.push(definition.name.clone());
+        for body_line in &definition.body {
+            let text = if self.takes_symbols(&body_line.text) {
+                substitute(&body_line.text, &substitutions)
+            } else {
+                body_line.text.clone()
+            };
+            let body_line = SourceLine {
+                text,
+                file: line.file.clone(),
+                number: line.number,
+                column: line.column,
+                expansion: Some(expansion.clone()),
+            };
+            self.expand_line(body_line, expanded, diagnostics);
+        }
+        self.active.pop();
+    }
+
+    // Whether the line is an A-instruction, a label or a macro invocation, the only places symbols are written.
+    fn takes_symbols(&self, text: &str) -> bool {
+        text.starts_with('@') || text.starts_with('(') || split_words(text).first().is_some_and(|name| self.macros.contains_key(*name))
+    }
+}
+
+// Parameters and arguments are separated by whitespace or commas.
+pub(crate) fn split_words(text: &str) -> Vec<&str> {
+    text.split(|c: char| c.is_whitespace() || c == ',').filter(|word| !word.is_empty()).collect()
+}
+
+// The rest of the line after the directive, if the line is that directive.
+pub(crate) fn directive<'a>(text: &'a str, name: &str) -> Option<&'a str> {
+    let rest = text.strip_prefix(name)?;
+    if rest.is_empty() || rest.starts_with(char::is_whitespace) {
+        Some(rest.trim())
+    } else {
+        None
+    }
+}
+
+// Replaces every symbol of the text found in substitutions, leaving the rest as it is.
+fn substitute(text: &str, substitutions: &HashMap<String, String>) -> String {
+    let is_symbol_character = |c: char| c.is_ascii_alphanumeric() || "_.$:".contains(c);
+    let mut result = String::new();
+    let mut symbol = String::new();
+    for c in text.chars().chain(std::iter::once('\n')) {
+        if is_symbol_character(c) {
+            symbol.push(c);
+            continue;
+        }
+        result.push_str(substitutions.get(&symbol).unwrap_or(&symbol));
+        symbol.clear();
+        if c != '\n' {
+            result.push(c);
+        }
+    }
+    result
+}
@@ -0,0 +1,184 @@
+// A command with comments and surrounding whitespace removed, remembering where it came from.
+#[derive(Clone)]
+pub struct SourceLine {
+    pub text: String,
+    // The file of the command, None when the source does not come from a file.
+    pub file: Option<String>,
+    // 1-based line number in the original file.
+    pub number: usize,
+    // 1-based column of the first character of `text` in the original line.
+    pub column: usize,
+    // The macro whose expansion produced the command, in which case `number` and `column` are those of the invocation.
+    pub expansion: Option<String>,
+}
+
+pub fn Create(lines: Vec<SourceLine>) -> Parser {
+    Parser {
+        lines,
+        current_index: std::cell::Cell::new(0),
+    }
+}
+
+pub enum CommandType {
+    A_COMMAND,
+    C_COMMAND,
+    L_COMMAND,
+}
+
+pub struct Parser {
+    lines: Vec<SourceLine>,
+    current_index: std::cell::Cell<usize>,
+}
+
+impl Parser {
+    pub fn hasMoreCommands(&self) -> bool {
+        self.current_index.get() < self.lines.len()
+    }
+    pub fn advance(&self) {
+        self.current_index.set(self.current_index.get() + 1);
+    }
+    pub fn commandType(&self) -> CommandType {
+        let current_command = self.current_command();
+        let first_character = current_command.chars().next().unwrap();
+        match first_character {
+            '@' => CommandType::A_COMMAND,
+            '(' => CommandType::L_COMMAND,
+            _ => CommandType::C_COMMAND,
+        }
+    }
+    pub fn symbol(&self) -> String {
+        match self.commandType() {
+            CommandType::A_COMMAND => {
+                let current_command = self.current_command();
+                String::from(&current_command[1..])
+            },
+            CommandType::L_COMMAND => {
+                let current_command = self.current_command();
+                let inner = &current_command[1..];
+                String::from(inner.strip_suffix(')').unwrap_or(inner))
+            },
+            _ => String::new(),
+        }
+    }
+    // The fields of a C-command are normalised to the mnemonics of the code tables, so that whitespace,
+    // lowercase, any order of the destination registers and the commutative form of comp are accepted.
+    pub fn dest(&self) -> String {
+        match self.commandType() {
+            CommandType::C_COMMAND => {
+                let (dest, _, _) = self.c_fields();
+                normalize_dest(dest)
+            },
+            _ => String::new(),
+        }
+    }
+    pub fn comp(&self) -> String {
+        match self.commandType() {
+            CommandType::C_COMMAND => {
+                let (_, comp, _) = self.c_fields();
+                normalize_comp(comp)
+            },
+            _ => String::new(),
+        }
+    }
+    pub fn jump(&self) -> String {
+        match self.commandType() {
+            CommandType::C_COMMAND => {
+                let (_, _, jump) = self.c_fields();
+                normalize_jump(jump)
+            },
+            _ => String::new(),
+        }
+    }
+    pub fn getCurrentLineNumber(&self) -> usize {
+        self.current_index.get()
+    }
+    pub fn getCurrentSourceLine(&self) -> &SourceLine {
+        self.lines.get(self.current_index.get()).unwrap()
+    }
+    fn current_command(&self) -> &str {
+        self.getCurrentSourceLine().text.as_str()
+    }
+    // Splits `dest=comp;jump` into its fields as written, empty when left out.
+    fn c_fields(&self) -> (&str, &str, &str) {
+        let current_command = self.current_command();
+        let (dest, rest) = current_command.split_once('=').unwrap_or(("", current_command));
+        let (comp, jump) = rest.split_once(';').unwrap_or((rest, ""));
+        (dest, comp, jump)
+    }
+}
+
+// Removes whitespace and turns mnemonics to uppercase, e.g. `d + m` becomes `D+M`.
+fn tokenize(field: &str) -> String {
+    field.chars().filter(|c| !c.is_whitespace()).map(|c| c.to_ascii_uppercase()).collect()
+}
+
+// The registers are written in the order of the code table, A then M then D, e.g. `DM` becomes `MD`.
+fn normalize_dest(dest: &str) -> String {
+    let dest = tokenize(dest);
+    if dest == "NULL" {
+        return String::from("null");
+    }
+    let mut dest: Vec<char> = dest.chars().collect();
+    if dest.iter().all(|c| "AMD".contains(*c)) {
+        dest.sort_by_key(|c| "AMD".find(*c));
+    }
+    dest.into_iter().collect()
+}
+
+// A commutative operation whose form is not in the code table is swapped, e.g. `M+D` becomes `D+M`.
+fn normalize_comp(comp: &str) -> String {
+    let comp = tokenize(comp);
+    let characters: Vec<char> = comp.chars().collect();
+    if let [x, op @ ('+' | '&' | '|'), y] = characters[..] {
+        let swapped = format!("{}{}{}", y, op, x);
+        if crate::code::comp(&comp).is_none() && crate::code::comp(&swapped).is_some() {
+            return swapped;
+        }
+    }
+    comp
+}
+
+fn normalize_jump(jump: &str) -> String {
+    let jump = tokenize(jump);
+    if jump == "NULL" {
+        String::from("null")
+    } else {
+        jump
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(source: &str) -> Vec<u16> {
+        crate::assemble(source).unwrap().words()
+    }
+
+    #[test]
+    fn dest_registers_are_sorted() {
+        assert_eq!(normalize_dest("DM"), "MD");
+        assert_eq!(normalize_dest("MA"), "AM");
+        assert_eq!(normalize_dest("DAM"), "AMD");
+        assert_eq!(normalize_dest("d m"), "MD");
+        assert_eq!(normalize_dest("Null"), "null");
+        assert_eq!(words("DM=D+1\nMA=D+1\nDAM=D+1\n"), words("MD=D+1\nAM=D+1\nAMD=D+1\n"));
+    }
+
+    #[test]
+    fn commutative_comp_is_swapped_only_when_needed() {
+        assert_eq!(normalize_comp("M+D"), "D+M");
+        assert_eq!(normalize_comp("A&D"), "D&A");
+        assert_eq!(normalize_comp("M|D"), "D|M");
+        assert_eq!(normalize_comp("D+M"), "D+M");
+        assert_eq!(normalize_comp("M-D"), "M-D");
+        assert_eq!(words("M=M+D\nD=A&D\n"), words("M=D+M\nD=D&A\n"));
+    }
+
+    #[test]
+    fn whitespace_and_case_are_ignored() {
+        assert_eq!(normalize_comp(" m + 1 "), "M+1");
+        assert_eq!(normalize_jump(" jgt"), "JGT");
+        assert_eq!(words("D = M + 1\nd=m+1\nam = d | a ; jne\n0 ; jmp\n"), words("D=M+1\nD=M+1\nAM=D|A;JNE\n0;JMP\n"));
+    }
+}
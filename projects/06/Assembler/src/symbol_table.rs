@@ -0,0 +1,137 @@
+pub fn Create() -> SymbolTable {
+    let predefined = [
+        (String::from("SP"),      0),
+        (String::from("LCL"),     1),
+        (String::from("ARG"),     2),
+        (String::from("THIS"),    3),
+        (String::from("THAT"),    4),
+        (String::from("R0"),      0),
+        (String::from("R1"),      1),
+        (String::from("R2"),      2),
+        (String::from("R3"),      3),
+        (String::from("R4"),      4),
+        (String::from("R5"),      5),
+        (String::from("R6"),      6),
+        (String::from("R7"),      7),
+        (String::from("R8"),      8),
+        (String::from("R9"),      9),
+        (String::from("R10"),     10),
+        (String::from("R11"),     11),
+        (String::from("R12"),     12),
+        (String::from("R13"),     13),
+        (String::from("R14"),     14),
+        (String::from("R15"),     15),
+        (String::from("SCREEN"),  16384),
+        (String::from("KBD"),     24576),
+    ];
+    let table = predefined.into_iter().map(|(symbol, address)| (symbol, (address, SymbolKind::Predefined))).collect();
+    SymbolTable {
+        table,
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SymbolKind {
+    Predefined,
+    Label,
+    Variable,
+}
+
+impl SymbolKind {
+    fn from_name(name: &str) -> Option<SymbolKind> {
+        match name {
+            "predefined" => Some(SymbolKind::Predefined),
+            "label" => Some(SymbolKind::Label),
+            "variable" => Some(SymbolKind::Variable),
+            _ => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            SymbolKind::Predefined => "predefined",
+            SymbolKind::Label => "label",
+            SymbolKind::Variable => "variable",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SymbolFileError {
+    // A line of the `.sym` file that is not `name kind address`.
+    BadLine { line: usize, text: String },
+}
+
+impl std::fmt::Display for SymbolFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SymbolFileError::BadLine { line, text } => write!(f, "line {}: `{}` is not `name kind address`", line, text),
+        }
+    }
+}
+
+pub struct SymbolTable {
+    table: std::collections::HashMap<String, (usize, SymbolKind)>,
+}
+
+impl SymbolTable {
+    pub fn addEntry(&mut self, symbol: String, address: usize, kind: SymbolKind) {
+        self.table.insert(symbol, (address, kind));
+    }
+    pub fn contains(&self, symbol: &str) -> bool {
+        self.table.contains_key(symbol)
+    }
+    pub fn GetAddress(&self, symbol: &str) -> usize {
+        self.table.get(symbol).unwrap().0
+    }
+    // All symbols of the given kind with their addresses, sorted by address and then by name.
+    pub fn entries(&self, kind: SymbolKind) -> Vec<(String, usize)> {
+        let mut entries: Vec<(String, usize)> = self.table.iter()
+            .filter(|(_, (_, k))| *k == kind)
+            .map(|(symbol, (address, _))| (symbol.clone(), *address))
+            .collect();
+        entries.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+        entries
+    }
+    // The `.sym` file: one `name kind address` line per symbol, sorted by address and then by name.
+    pub fn export(&self) -> String {
+        let mut entries: Vec<(&String, &(usize, SymbolKind))> = self.table.iter().collect();
+        entries.sort_by(|a, b| (a.1.0, a.0).cmp(&(b.1.0, b.0)));
+        let lines: Vec<String> = entries.iter().map(|(symbol, (address, kind))| format!("{} {} {}", symbol, kind.name(), address)).collect();
+        lines.join("\n")
+    }
+    // Reads the `.sym` file at path for the command-line tools, exiting with an error if it cannot be read or imported.
+    pub fn load_or_exit(path: &str) -> SymbolTable {
+        let imported = std::fs::read_to_string(path)
+            .map_err(|error| error.to_string())
+            .and_then(|contents| Self::import(&contents).map_err(|error| error.to_string()));
+        match imported {
+            Ok(symbol_table) => symbol_table,
+            Err(error) => {
+                eprintln!("{}: error: {}", path, error);
+                std::process::exit(1);
+            },
+        }
+    }
+    // Reads a `.sym` file written by export. Only the symbols of the file are in the table.
+    pub fn import(contents: &str) -> Result<SymbolTable, SymbolFileError> {
+        let mut table = std::collections::HashMap::new();
+        for (index, line) in contents.split('\n').enumerate() {
+            let text = line.trim();
+            if text.is_empty() {
+                continue;
+            }
+            let words: Vec<&str> = text.split_whitespace().collect();
+            let entry = match words[..] {
+                [symbol, kind, address] => SymbolKind::from_name(kind).zip(address.parse().ok()).map(|(kind, address)| (symbol, kind, address)),
+                _ => None,
+            };
+            match entry {
+                Some((symbol, kind, address)) => table.insert(String::from(symbol), (address, kind)),
+                None => return Err(SymbolFileError::BadLine { line: index + 1, text: String::from(text) }),
+            };
+        }
+        Ok(SymbolTable {
+            table,
+        })
+    }
+}
@@ -1,11 +1,38 @@
 
+use super::optimizer;
 use super::parser;
 
+// A VM command, with where it comes from and where its code begins in the contents.
+// Commands folded into one by the optimizer are recorded together, from the line of the first to that of the last.
+struct SourceCommand {
+    path: String,
+    line: usize,
+    end_line: usize,
+    command: String,
+    offset: usize,
+}
+
 pub struct CodeWriter {
     filename_output: String,
     contents: String,
     label_count: usize,
     filename_vm: String,
+    path_vm: String,
+    function_name: String,
+    source_commands: Vec<SourceCommand>,
+    // A `<output>.map` file is written next to the output, mapping every VM command to its lines.
+    source_map: bool,
+    // Every VM command is written as a comment before its code.
+    source_comments: bool,
+    // Comparisons jump to one routine per operator instead of being written inline.
+    shared_comparison: bool,
+    shared_comparison_used: Vec<parser::ArithmeticOp>,
+    comparison_count: usize,
+    inline_comparison_instructions: usize,
+    // gt and lt check the signs of the operands first, so that x-y never overflows.
+    safe_comparison: bool,
+    // Instructions that cancel each other out are removed when writing the output.
+    optimization: bool,
 }
 
 impl CodeWriter {
@@ -15,38 +42,248 @@ impl CodeWriter {
             contents: String::new(),
             label_count: 0,
             filename_vm: String::new(),
+            path_vm: String::new(),
+            function_name: String::new(),
+            source_commands: vec![],
+            source_map: false,
+            source_comments: false,
+            shared_comparison: false,
+            shared_comparison_used: vec![],
+            comparison_count: 0,
+            inline_comparison_instructions: 0,
+            safe_comparison: false,
+            optimization: false,
         }
     }
-    pub fn setFileName(&mut self, filename: &String) {
+    pub fn enableSharedComparison(&mut self) {
+        self.shared_comparison = true;
+    }
+    pub fn enableSafeComparison(&mut self) {
+        self.safe_comparison = true;
+    }
+    pub fn enableOptimization(&mut self) {
+        self.optimization = true;
+    }
+    pub fn enableSourceMap(&mut self) {
+        self.source_map = true;
+    }
+    pub fn enableSourceComments(&mut self) {
+        self.source_comments = true;
+    }
+    pub fn setFileName(&mut self, filename: &str) {
+        self.path_vm = String::from(filename);
         self.filename_vm = Self::get_vm_filename_from_full_path(filename);
+        // Labels outside of any function are scoped to the file.
+        self.function_name = self.filename_vm.clone();
+    }
+    // Records that the code written from now on comes from the command at line of the current file.
+    // When a move is written for two commands, the first one has no code of its own.
+    pub fn setSourceCommand(&mut self, line: usize, command: &parser::VmCommand) {
+        self.setSourceCommands(&[(line, command.clone())]);
     }
-    pub fn writeArithmetic(&mut self, command: &str) {
+    // Same as setSourceCommand, for the code of several commands of the current file folded into one.
+    pub fn setSourceCommands(&mut self, commands: &[(usize, parser::VmCommand)]) {
+        let texts: Vec<String> = commands.iter().map(|(_, command)| command.to_string()).collect();
+        self.source_commands.push(SourceCommand {
+            path: self.path_vm.clone(),
+            line: commands.first().unwrap().0,
+            end_line: commands.last().unwrap().0,
+            command: texts.join(", "),
+            offset: self.contents.len(),
+        });
+    }
+    pub fn writeCommand(&mut self, command: &parser::VmCommand) {
         match command {
-            "add" => self.arithmetic_add(),
-            "sub" => self.arithmetic_sub(),
-            "neg" => self.arithmetic_neg(),
-            "eq" => self.arithmetic_eq(),
-            "gt" => self.arithmetic_gt(),
-            "lt" => self.arithmetic_lt(),
-            "and" => self.arithmetic_and(),
-            "or" => self.arithmetic_or(),
-            "not" => self.arithmetic_not(),
-            _ => panic!("invalid command !"),
+            parser::VmCommand::Arithmetic(op) => self.writeArithmetic(*op),
+            parser::VmCommand::Push(..) | parser::VmCommand::Pop(..) => self.writePushPop(command),
+            parser::VmCommand::Label(label) => self.writeLabel(label),
+            parser::VmCommand::Goto(label) => self.writeGoto(label),
+            parser::VmCommand::If(label) => self.writeIf(label),
+            parser::VmCommand::Function(function_name, num_locals) => self.writeFunction(function_name, *num_locals),
+            parser::VmCommand::Call(function_name, num_args) => self.writeCall(function_name, *num_args),
+            parser::VmCommand::Return => self.writeReturn(),
+        }
+    }
+    pub fn writeArithmetic(&mut self, op: parser::ArithmeticOp) {
+        match op {
+            parser::ArithmeticOp::Add => self.arithmetic_add(),
+            parser::ArithmeticOp::Sub => self.arithmetic_sub(),
+            parser::ArithmeticOp::Neg => self.arithmetic_neg(),
+            parser::ArithmeticOp::Eq => self.arithmetic_eq(),
+            parser::ArithmeticOp::Gt => self.arithmetic_gt(),
+            parser::ArithmeticOp::Lt => self.arithmetic_lt(),
+            parser::ArithmeticOp::And => self.arithmetic_and(),
+            parser::ArithmeticOp::Or => self.arithmetic_or(),
+            parser::ArithmeticOp::Not => self.arithmetic_not(),
         }
     }
-    pub fn writePushPop(&mut self, command: parser::CommandType, segment: &str, index: i32) {
+    pub fn writePushPop(&mut self, command: &parser::VmCommand) {
         match command {
-            parser::CommandType::C_PUSH => self.stack_push(segment, index),
-            parser::CommandType::C_POP => self.stack_pop(segment, index),
+            parser::VmCommand::Push(segment, index) => self.stack_push(*segment, *index),
+            parser::VmCommand::Pop(segment, index) => self.stack_pop(*segment, *index),
             _ => panic!("invalid command !"),
         }
     }
+    // Writes `push source source_index` followed by `pop target target_index` as a single move, without going through the stack.
+    pub fn writeMove(&mut self, source: parser::Segment, source_index: u16, target: parser::Segment, target_index: u16) {
+        self.stack_move(source, source_index, target, target_index);
+    }
+    pub fn writeInit(&mut self) {
+        self.bootstrap();
+    }
+    pub fn writeLabel(&mut self, label: &str) {
+        self.flow_label(label);
+    }
+    pub fn writeGoto(&mut self, label: &str) {
+        self.flow_goto(label);
+    }
+    pub fn writeIf(&mut self, label: &str) {
+        self.flow_if(label);
+    }
+    pub fn writeFunction(&mut self, function_name: &str, num_locals: u16) {
+        self.function_declare(function_name, num_locals);
+    }
+    pub fn writeCall(&mut self, function_name: &str, num_args: u16) {
+        self.function_call(function_name, num_args);
+    }
+    pub fn writeReturn(&mut self) {
+        self.function_return();
+    }
+    // Writes the translated code, to standard output when the output filename is `-`.
+    // The source map is written to `<output>.map`, unless the code goes to standard output.
     pub fn close(&self) -> std::io::Result<()> {
-        let mut buffer = std::fs::File::create(&self.filename_output)?;
-        std::io::Write::write_all(&mut buffer, self.contents.as_bytes());
+        let (contents, line_ranges) = self.render();
+        if self.filename_output == "-" {
+            let mut buffer = std::io::stdout();
+            std::io::Write::write_all(&mut buffer, contents.as_bytes())?;
+        } else {
+            let mut buffer = std::fs::File::create(&self.filename_output)?;
+            std::io::Write::write_all(&mut buffer, contents.as_bytes())?;
+            if self.source_map {
+                let mut buffer = std::fs::File::create(format!("{}.map", self.filename_output))?;
+                std::io::Write::write_all(&mut buffer, self.source_map_json(&line_ranges).as_bytes())?;
+            }
+        }
         Ok(())
     }
-    fn get_vm_filename_from_full_path(path: &String) -> String {
+    // The number of instructions saved by the shared comparison routines, compared to writing every comparison inline.
+    // It is negative when there are too few comparisons to pay for the routines.
+    pub fn getSharedComparisonSaving(&self) -> isize {
+        let inline = self.inline_comparison_instructions;
+        let shared = self.comparison_count * Self::count_instructions(&Self::comparison_call_site("comp_eq", "comp_return_0"))
+            + Self::count_instructions(&self.shared_comparison_routines());
+        inline as isize - shared as isize
+    }
+    // The translated code, as written by close.
+    pub fn getCode(&self) -> String {
+        self.get_contents()
+    }
+    // The number of instructions of the translated code, labels excluded.
+    pub fn getInstructionCount(&self) -> usize {
+        Self::count_instructions(&self.get_contents())
+    }
+    fn get_contents(&self) -> String {
+        self.render().0
+    }
+    // The translated code, with the first and last line of the code of every source command, if it has any.
+    fn render(&self) -> (String, Vec<Option<(usize, usize)>>) {
+        let mut lines = self.tagged_lines();
+        if self.optimization {
+            let tagged: Vec<(Option<usize>, &str)> = lines.iter().map(|(tag, line)| (*tag, line.as_str())).collect();
+            lines = optimizer::peephole(&tagged);
+            lines.push((None, String::new()));
+        }
+        let mut output = vec![];
+        let mut line_ranges: Vec<Option<(usize, usize)>> = vec![None; self.source_commands.len()];
+        let mut commented = 0;
+        for (tag, line) in lines {
+            if let Some(index) = tag {
+                if self.source_comments {
+                    // Also comments the commands before, whose code was merged into this one.
+                    while commented <= index {
+                        output.push(self.source_comment(commented));
+                        commented += 1;
+                    }
+                }
+                if !line.trim().is_empty() {
+                    let number = output.len() + 1;
+                    let start = line_ranges[index].map_or(number, |(start, _)| start);
+                    line_ranges[index] = Some((start, number));
+                }
+            }
+            output.push(line);
+        }
+        (output.join("\n"), line_ranges)
+    }
+    // The lines of the code, each tagged with the index of its source command, or None for code of no command.
+    fn tagged_lines(&self) -> Vec<(Option<usize>, String)> {
+        let routines = self.shared_comparison_routines();
+        let first_offset = self.source_commands.first().map_or(self.contents.len(), |command| command.offset);
+        let mut segments = vec![(None, routines.as_str()), (None, &self.contents[..first_offset])];
+        for (index, command) in self.source_commands.iter().enumerate() {
+            let end = self.source_commands.get(index + 1).map_or(self.contents.len(), |next| next.offset);
+            segments.push((Some(index), &self.contents[command.offset..end]));
+        }
+        let mut lines: Vec<(Option<usize>, String)> = vec![];
+        for (tag, segment) in segments {
+            // Code begins with a newline, so the first piece belongs to the last line of the previous segment.
+            let mut pieces = segment.split('\n');
+            let first = pieces.next().unwrap();
+            match lines.last_mut() {
+                Some((_, line)) => line.push_str(first),
+                None => lines.push((tag, String::from(first))),
+            }
+            lines.extend(pieces.map(|piece| (tag, String::from(piece))));
+        }
+        lines
+    }
+    fn source_comment(&self, index: usize) -> String {
+        let command = &self.source_commands[index];
+        if command.line == command.end_line {
+            format!("// {}:{}: {}", command.path, command.line, command.command)
+        } else {
+            format!("// {}:{}-{}: {}", command.path, command.line, command.end_line, command.command)
+        }
+    }
+    // Every source command with its lines in the output, or null when it has no code of its own.
+    fn source_map_json(&self, line_ranges: &[Option<(usize, usize)>]) -> String {
+        let mut entries = vec![];
+        for (command, range) in self.source_commands.iter().zip(line_ranges) {
+            let asm = match range {
+                Some((start, end)) => format!("[{}, {}]", start, end),
+                None => String::from("null"),
+            };
+            entries.push(format!(
+                "    {{\"source\": {}, \"line\": {}, \"end_line\": {}, \"command\": {}, \"asm\": {}}}",
+                Self::json_string(&command.path),
+                command.line,
+                command.end_line,
+                Self::json_string(&command.command),
+                asm
+            ));
+        }
+        format!("{{\n  \"file\": {},\n  \"commands\": [\n{}\n  ]\n}}\n", Self::json_string(&self.filename_output), entries.join(",\n"))
+    }
+    fn json_string(text: &str) -> String {
+        let mut escaped = String::from("\"");
+        for c in text.chars() {
+            match c {
+                '"' => escaped.push_str("\\\""),
+                '\\' => escaped.push_str("\\\\"),
+                c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+                c => escaped.push(c),
+            }
+        }
+        escaped.push('"');
+        escaped
+    }
+    fn count_instructions(code: &str) -> usize {
+        code.lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('(') && !line.starts_with("//"))
+            .count()
+    }
+    pub fn get_vm_filename_from_full_path(path: &str) -> String {
         let mut split = path.split("/").last().unwrap();
         split = split.split("\\").last().unwrap();
         split = split.split(".vm").nth(0).unwrap();
@@ -108,8 +345,52 @@ M=-M
         ");
     }
     fn arithmetic_eq(&mut self) {
+        self.arithmetic_compare(parser::ArithmeticOp::Eq);
+    }
+    fn arithmetic_gt(&mut self) {
+        self.arithmetic_compare(parser::ArithmeticOp::Gt);
+    }
+    fn arithmetic_lt(&mut self) {
+        self.arithmetic_compare(parser::ArithmeticOp::Lt);
+    }
+    fn arithmetic_compare(&mut self, op: parser::ArithmeticOp) {
+        let jump = Self::comparison_jump(op);
         let number = self.label_count;
-        self.contents.push_str(format!("
+        self.label_count += 1;
+        self.comparison_count += 1;
+        self.inline_comparison_instructions += Self::count_instructions(&self.comparison_body(jump, "comp"));
+        if self.shared_comparison {
+            if !self.shared_comparison_used.contains(&op) {
+                self.shared_comparison_used.push(op);
+            }
+            let routine = format!("comp_{}", op.name());
+            let return_address = format!("comp_return_{number}");
+            self.contents.push_str(&Self::comparison_call_site(&routine, &return_address));
+        } else {
+            let body = self.comparison_body(jump, &format!("comp_{number}"));
+            self.contents.push_str(&body);
+        }
+    }
+    fn comparison_jump(op: parser::ArithmeticOp) -> &'static str {
+        match op {
+            parser::ArithmeticOp::Eq => "JEQ",
+            parser::ArithmeticOp::Gt => "JGT",
+            parser::ArithmeticOp::Lt => "JLT",
+            _ => panic!("invalid command !"),
+        }
+    }
+    fn comparison_body(&self, jump: &str, label: &str) -> String {
+        if self.safe_comparison && jump != "JEQ" {
+            Self::comparison_body_safe(jump, label)
+        } else {
+            Self::comparison_body_fast(jump, label)
+        }
+    }
+    // Pops y and x, then pushes -1(=true) if x-y satisfies the jump condition, or 0(=false) otherwise.
+    // x-y overflows when x and y have different signs and are far apart, e.g. 32767 gt -2, which gives a wrong answer.
+    // It is always right for eq though, since x-y wraps to 0 only when x is equal to y.
+    fn comparison_body_fast(jump: &str, label: &str) -> String {
+        format!("
 @SP
 AM=M-1
 D=M
@@ -117,52 +398,104 @@ D=M
 A=M-1
 D=M-D
 M=-1
-@comp_{number}
-D;JEQ
+@{label}
+D;{jump}
 @SP
 A=M-1
 M=0
-(comp_{number})
-        ").as_str());
-        self.label_count += 1;
+({label})
+        ")
     }
-    fn arithmetic_gt(&mut self) {
-        let number = self.label_count;
-        self.contents.push_str(format!("
+    // Same as the fast version, except that D holds a value with the sign of x-y instead of x-y itself.
+    // @SP ~ D=M(x)                 : Pop y into R13, and put x into register D.
+    // @{label}_x_negative ~ D=1    : If x>=0 and y>=0, x-y cannot overflow. If x>=0 and y<0, x>y so 1 will do.
+    // ({label}_x_negative) ~ D=-1  : If x<0 and y<0, x-y cannot overflow. If x<0 and y>=0, x<y so -1 will do.
+    // ({label}_difference) ~ D=M-D : Put x-y into register D, which is safe as x and y have the same sign.
+    // ({label}_compare) ~          : Same as the fast version.
+    fn comparison_body_safe(jump: &str, label: &str) -> String {
+        format!("
 @SP
 AM=M-1
 D=M
+@R13
+M=D
 @SP
 A=M-1
-D=M-D
-M=-1
-@comp_{number}
-D;JGT
-@SP
-A=M-1
-M=0
-(comp_{number})
-        ").as_str());
-        self.label_count += 1;
-    }
-    fn arithmetic_lt(&mut self) {
-        let number = self.label_count;
-        self.contents.push_str(format!("
-@SP
-AM=M-1
+D=M
+@{label}_x_negative
+D;JLT
+@R13
+D=M
+@{label}_difference
+D;JGE
+D=1
+@{label}_compare
+0;JMP
+({label}_x_negative)
+@R13
+D=M
+@{label}_difference
+D;JLT
+D=-1
+@{label}_compare
+0;JMP
+({label}_difference)
+@R13
 D=M
 @SP
 A=M-1
 D=M-D
+({label}_compare)
+@SP
+A=M-1
 M=-1
-@comp_{number}
-D;JLT
+@{label}
+D;{jump}
 @SP
 A=M-1
 M=0
-(comp_{number})
-        ").as_str());
-        self.label_count += 1;
+({label})
+        ")
+    }
+    // Put the return address into R14 and jump to the routine, which jumps back to RAM[R14] when done.
+    fn comparison_call_site(routine: &str, return_address: &str) -> String {
+        format!("
+@{return_address}
+D=A
+@R14
+M=D
+@{routine}
+0;JMP
+({return_address})
+        ")
+    }
+    // The routines of the comparisons in use, preceded by a jump over them so that execution starts after them.
+    fn shared_comparison_routines(&self) -> String {
+        let mut routines = String::new();
+        if self.shared_comparison_used.is_empty() {
+            return routines;
+        }
+        routines.push_str("
+@comp_start
+0;JMP
+        ");
+        for op in &self.shared_comparison_used {
+            let jump = Self::comparison_jump(*op);
+            let routine = format!("comp_{}", op.name());
+            routines.push_str(format!("
+({routine})
+            ").as_str());
+            routines.push_str(&self.comparison_body(jump, &format!("{routine}_end")));
+            routines.push_str("
+@R14
+A=M
+0;JMP
+            ");
+        }
+        routines.push_str("
+(comp_start)
+        ");
+        routines
     }
     fn arithmetic_and(&mut self) {
         self.contents.push_str("
@@ -194,17 +527,16 @@ M=!M
 }
 
 impl CodeWriter {
-    fn stack_push(&mut self, segment: &str, index: i32) {
+    fn stack_push(&mut self, segment: parser::Segment, index: u16) {
         match segment {
-            "local" => self.stack_push_local(index),
-            "argument" => self.stack_push_argument(index),
-            "this" => self.stack_push_this(index),
-            "that" => self.stack_push_that(index),
-            "pointer" => self.stack_push_pointer(index),
-            "temp" => self.stack_push_temp(index),
-            "constant" => self.stack_push_constant(index),
-            "static" => self.stack_push_static(index),
-            _ => panic!("invalid command !"),
+            parser::Segment::Local => self.stack_push_local(index),
+            parser::Segment::Argument => self.stack_push_argument(index),
+            parser::Segment::This => self.stack_push_this(index),
+            parser::Segment::That => self.stack_push_that(index),
+            parser::Segment::Pointer => self.stack_push_pointer(index),
+            parser::Segment::Temp => self.stack_push_temp(index),
+            parser::Segment::Constant => self.stack_push_constant(index),
+            parser::Segment::Static => self.stack_push_static(index),
         }
     }
     //          (A: ?) (D: ?)
@@ -214,7 +546,7 @@ impl CodeWriter {
     //          (A: index) (D: index)
     // @LCL     : Put 1(=value of LCL) into register A.
     //          (A: 1) (D: index)
-    // A=M+D    : M+D is equal to RAM[1]+index. Put RAM[1]+index into register A, which indicates address of local segment.
+    // A=D+M    : D+M is equal to RAM[1]+index. Put RAM[1]+index into register A, which indicates address of local segment.
     //          (A: RAM[1]+index) (D: index)
     // D=M      : Put RAM[RAM[1]+index] into register D, which is value of local segment.
     //          (A: RAM[1]+index) (D: RAM[RAM[1]+index])
@@ -228,12 +560,12 @@ impl CodeWriter {
     //          (A: 0) (D: RAM[RAM[1]+index])
     // M=M+1    : Put RAM[0]+1 into RAM[0] to move SP.
     //          (A: 0) (D: RAM[RAM[1]+index])
-    fn stack_push_local(&mut self, index: i32) {
+    fn stack_push_local(&mut self, index: u16) {
         self.contents.push_str(format!("
 @{index}
 D=A
 @LCL
-A=M+D
+A=D+M
 D=M
 @SP
 A=M
@@ -242,12 +574,12 @@ M=D
 M=M+1
         ").as_str());
     }
-    fn stack_push_argument(&mut self, index: i32) {
+    fn stack_push_argument(&mut self, index: u16) {
         self.contents.push_str(format!("
 @{index}
 D=A
 @ARG
-A=M+D
+A=D+M
 D=M
 @SP
 A=M
@@ -256,12 +588,12 @@ M=D
 M=M+1
         ").as_str());
     }
-    fn stack_push_this(&mut self, index: i32) {
+    fn stack_push_this(&mut self, index: u16) {
         self.contents.push_str(format!("
 @{index}
 D=A
 @THIS
-A=M+D
+A=D+M
 D=M
 @SP
 A=M
@@ -270,12 +602,12 @@ M=D
 M=M+1
         ").as_str());
     }
-    fn stack_push_that(&mut self, index: i32) {
+    fn stack_push_that(&mut self, index: u16) {
         self.contents.push_str(format!("
 @{index}
 D=A
 @THAT
-A=M+D
+A=D+M
 D=M
 @SP
 A=M
@@ -284,12 +616,12 @@ M=D
 M=M+1
         ").as_str());
     }
-    fn stack_push_pointer(&mut self, index: i32) {
+    fn stack_push_pointer(&mut self, index: u16) {
         self.contents.push_str(format!("
 @{index}
 D=A
 @3
-A=A+D
+A=D+A
 D=M
 @SP
 A=M
@@ -298,12 +630,12 @@ M=D
 M=M+1
         ").as_str());
     }
-    fn stack_push_temp(&mut self, index: i32) {
+    fn stack_push_temp(&mut self, index: u16) {
         self.contents.push_str(format!("
 @{index}
 D=A
 @5
-A=A+D
+A=D+A
 D=M
 @SP
 A=M
@@ -312,7 +644,7 @@ M=D
 M=M+1
         ").as_str());
     }
-    fn stack_push_constant(&mut self, index: i32) {
+    fn stack_push_constant(&mut self, index: u16) {
         self.contents.push_str(format!("
 @{index}
 D=A
@@ -323,7 +655,7 @@ M=D
 M=M+1
         ").as_str());
     }
-    fn stack_push_static(&mut self, index: i32) {
+    fn stack_push_static(&mut self, index: u16) {
         let vm_name = self.filename_vm.clone();
         self.contents.push_str(format!("
 @{vm_name}.{index}
@@ -338,17 +670,16 @@ M=M+1
 }
 
 impl CodeWriter {
-    fn stack_pop(&mut self, segment: &str, index: i32) {
+    fn stack_pop(&mut self, segment: parser::Segment, index: u16) {
         match segment {
-            "local" => self.stack_pop_local(index),
-            "argument" => self.stack_pop_argument(index),
-            "this" => self.stack_pop_this(index),
-            "that" => self.stack_pop_that(index),
-            "pointer" => self.stack_pop_pointer(index),
-            "temp" => self.stack_pop_temp(index),
-            "constant" => self.stack_pop_constant(index),
-            "static" => self.stack_pop_static(index),
-            _ => panic!("invalid command !"),
+            parser::Segment::Local => self.stack_pop_local(index),
+            parser::Segment::Argument => self.stack_pop_argument(index),
+            parser::Segment::This => self.stack_pop_this(index),
+            parser::Segment::That => self.stack_pop_that(index),
+            parser::Segment::Pointer => self.stack_pop_pointer(index),
+            parser::Segment::Temp => self.stack_pop_temp(index),
+            parser::Segment::Constant => unreachable!("pop constant is rejected by the parser"),
+            parser::Segment::Static => self.stack_pop_static(index),
         }
     }
     //          (A: ?) (D: ?)
@@ -358,7 +689,7 @@ impl CodeWriter {
     //          (A: index) (D: index)
     // @LCL     : Put 1(=value of LCL) into register A.
     //          (A: 1) (D: index)
-    // D=M+D    : M+D is equal to RAM[1]+index. Put it into register D, which is address of local segment.
+    // D=D+M    : D+M is equal to RAM[1]+index. Put it into register D, which is address of local segment.
     //          (A: 1) (D: RAM[1]+index)
     // @R13     : Put 13(=value of R13) into register A.
     //          (A: 13) (D: RAM[1]+index)
@@ -378,12 +709,12 @@ impl CodeWriter {
     //          (A: RAM[1]+index) (D: RAM[X-1])
     // M=D      : Put RAM[X-1] into RAM[RAM[1]+index]. The local segment whose location is RAM[1]+index will be updated.
     //          (A: RAM[1]+index) (D: RAM[X-1])
-    fn stack_pop_local(&mut self, index: i32) {
+    fn stack_pop_local(&mut self, index: u16) {
         self.contents.push_str(format!("
 @{index}
 D=A
 @LCL
-D=M+D
+D=D+M
 @R13
 M=D
 @SP
@@ -394,12 +725,12 @@ A=M
 M=D
         ").as_str());
     }
-    fn stack_pop_argument(&mut self, index: i32) {
+    fn stack_pop_argument(&mut self, index: u16) {
         self.contents.push_str(format!("
 @{index}
 D=A
 @ARG
-D=M+D
+D=D+M
 @R13
 M=D
 @SP
@@ -410,12 +741,12 @@ A=M
 M=D
         ").as_str());
     }
-    fn stack_pop_this(&mut self, index: i32) {
+    fn stack_pop_this(&mut self, index: u16) {
         self.contents.push_str(format!("
 @{index}
 D=A
 @THIS
-D=M+D
+D=D+M
 @R13
 M=D
 @SP
@@ -426,12 +757,12 @@ A=M
 M=D
         ").as_str());
     }
-    fn stack_pop_that(&mut self, index: i32) {
+    fn stack_pop_that(&mut self, index: u16) {
         self.contents.push_str(format!("
 @{index}
 D=A
 @THAT
-D=M+D
+D=D+M
 @R13
 M=D
 @SP
@@ -442,12 +773,12 @@ A=M
 M=D
         ").as_str());
     }
-    fn stack_pop_pointer(&mut self, index: i32) {
+    fn stack_pop_pointer(&mut self, index: u16) {
         self.contents.push_str(format!("
 @{index}
 D=A
 @3
-D=A+D
+D=D+A
 @R13
 M=D
 @SP
@@ -458,12 +789,12 @@ A=M
 M=D
         ").as_str());
     }
-    fn stack_pop_temp(&mut self, index: i32) {
+    fn stack_pop_temp(&mut self, index: u16) {
         self.contents.push_str(format!("
 @{index}
 D=A
 @5
-D=A+D
+D=D+A
 @R13
 M=D
 @SP
@@ -474,10 +805,7 @@ A=M
 M=D
         ").as_str());
     }
-    fn stack_pop_constant(&mut self, index: i32) {
-        panic!("[pop constant] cannot be executed as constant is not stored in memory");
-    }
-    fn stack_pop_static(&mut self, index: i32) {
+    fn stack_pop_static(&mut self, index: u16) {
         let vm_name = self.filename_vm.clone();
         self.contents.push_str(format!("
 @SP
@@ -487,4 +815,403 @@ D=M
 M=D
         ").as_str());
     }
-}
\ No newline at end of file
+}
+impl CodeWriter {
+    // Pointer, temp and static are at fixed addresses, so the value is stored there directly.
+    // Otherwise, the address is computed into R13 first, since loading the value may need register D.
+    fn stack_move(&mut self, source: parser::Segment, source_index: u16, target: parser::Segment, target_index: u16) {
+        let load = self.segment_load(source, source_index);
+        let vm_name = self.filename_vm.clone();
+        let code = match target {
+            parser::Segment::Pointer => format!("{load}\n@{}\nM=D", 3 + target_index),
+            parser::Segment::Temp => format!("{load}\n@{}\nM=D", 5 + target_index),
+            parser::Segment::Static => format!("{load}\n@{vm_name}.{target_index}\nM=D"),
+            parser::Segment::Constant => unreachable!("pop constant is rejected by the parser"),
+            _ => {
+                let base = Self::segment_base(target);
+                format!("@{target_index}\nD=A\n@{base}\nD=D+M\n@R13\nM=D\n{load}\n@R13\nA=M\nM=D")
+            },
+        };
+        self.contents.push_str(format!("\n{code}\n        ").as_str());
+    }
+    // Puts the value of the segment at index into register D.
+    fn segment_load(&self, segment: parser::Segment, index: u16) -> String {
+        match segment {
+            parser::Segment::Pointer => format!("@{}\nD=M", 3 + index),
+            parser::Segment::Temp => format!("@{}\nD=M", 5 + index),
+            parser::Segment::Constant => format!("@{index}\nD=A"),
+            parser::Segment::Static => format!("@{}.{index}\nD=M", self.filename_vm),
+            _ => format!("@{index}\nD=A\n@{}\nA=D+M\nD=M", Self::segment_base(segment)),
+        }
+    }
+    // The register holding the base address of local, argument, this and that.
+    fn segment_base(segment: parser::Segment) -> &'static str {
+        match segment {
+            parser::Segment::Local => "LCL",
+            parser::Segment::Argument => "ARG",
+            parser::Segment::This => "THIS",
+            parser::Segment::That => "THAT",
+            _ => panic!("segment {} has no base address !", segment.name()),
+        }
+    }
+}
+
+impl CodeWriter {
+    // Labels are only visible inside the function declaring them, so they are written as `Function$label`.
+    fn get_label_in_function(&self, label: &str) -> String {
+        format!("{}${}", self.function_name, label)
+    }
+    fn flow_label(&mut self, label: &str) {
+        let label = self.get_label_in_function(label);
+        self.contents.push_str(format!("
+({label})
+        ").as_str());
+    }
+    fn flow_goto(&mut self, label: &str) {
+        let label = self.get_label_in_function(label);
+        self.contents.push_str(format!("
+@{label}
+0;JMP
+        ").as_str());
+    }
+    //          (A: ?) (D: ?) (RAM[0]: ?)
+    // @SP      : Put SP into register A. Let us suppose current RAM[0] is X.
+    //          (A: 0) (D: ?) (RAM[0]: X)
+    // AM=M-1   : Pop the top of the stack. Put X-1 into register A and RAM[0].
+    //          (A: X-1) (D: ?) (RAM[0]: X-1)
+    // D=M      : Put RAM[X-1], the popped value, into register D.
+    //          (A: X-1) (D: RAM[X-1]) (RAM[0]: X-1)
+    // @{label} : Put address of the label into register A.
+    //          (A: label) (D: RAM[X-1]) (RAM[0]: X-1)
+    // D;JNE    : Jump to the label if the popped value is not false(=0).
+    fn flow_if(&mut self, label: &str) {
+        let label = self.get_label_in_function(label);
+        self.contents.push_str(format!("
+@SP
+AM=M-1
+D=M
+@{label}
+D;JNE
+        ").as_str());
+    }
+}
+
+impl CodeWriter {
+    // @256 ~ M=D       : Put 256 into SP, where the stack begins.
+    // call Sys.init 0  : Call the entry point of the program, which is expected never to return.
+    fn bootstrap(&mut self) {
+        self.function_name = String::from("Bootstrap");
+        self.contents.push_str("
+@256
+D=A
+@SP
+M=D
+        ");
+        self.function_call("Sys.init", 0);
+    }
+    // (function_name) : Entry point of the function.
+    // Then, push 0 as many times as the number of local variables to initialize them.
+    fn function_declare(&mut self, function_name: &str, num_locals: u16) {
+        self.function_name = String::from(function_name);
+        self.contents.push_str(format!("
+({function_name})
+        ").as_str());
+        for _ in 0..num_locals {
+            self.contents.push_str("
+@SP
+A=M
+M=0
+@SP
+M=M+1
+            ");
+        }
+    }
+    // The frame of the caller is saved on the stack, in this order.
+    // - return address : Label put right after the jump, unique for every call site.
+    // - LCL, ARG, THIS, THAT : Segments of the caller, restored on return.
+    // Then, ARG is repositioned to SP-5-num_args, where the arguments were pushed by the caller,
+    // and LCL is repositioned to SP, where the callee will push its local variables.
+    fn function_call(&mut self, function_name: &str, num_args: u16) {
+        let return_address = format!("{}$ret.{}", self.function_name, self.label_count);
+        self.label_count += 1;
+        self.contents.push_str(format!("
+@{return_address}
+D=A
+@SP
+A=M
+M=D
+@SP
+M=M+1
+        ").as_str());
+        for segment in ["LCL", "ARG", "THIS", "THAT"] {
+            self.contents.push_str(format!("
+@{segment}
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+            ").as_str());
+        }
+        let offset = 5 + num_args;
+        self.contents.push_str(format!("
+@SP
+D=M
+@{offset}
+D=D-A
+@ARG
+M=D
+@SP
+D=M
+@LCL
+M=D
+@{function_name}
+0;JMP
+({return_address})
+        ").as_str());
+    }
+    // @LCL ~ M=D(R13)     : Save LCL, the end of the frame, into R13.
+    // @5 ~ M=D(R14)       : Save RAM[LCL-5], the return address, into R14. It would be overwritten by the return value when there is no argument.
+    // @SP ~ M=D(ARG)      : Pop the return value into RAM[ARG], where the caller expects it.
+    // @ARG ~ M=D(SP)      : Put ARG+1 into SP, right after the return value.
+    // @R13 ~ M=D(LCL)     : Restore THAT, THIS, ARG and LCL of the caller, walking R13 back through the frame.
+    // @R14 ~ 0;JMP        : Jump to the return address.
+    fn function_return(&mut self) {
+        self.contents.push_str("
+@LCL
+D=M
+@R13
+M=D
+@5
+A=D-A
+D=M
+@R14
+M=D
+@SP
+AM=M-1
+D=M
+@ARG
+A=M
+M=D
+@ARG
+D=M+1
+@SP
+M=D
+        ");
+        for segment in ["THAT", "THIS", "ARG", "LCL"] {
+            self.contents.push_str(format!("
+@R13
+AM=M-1
+D=M
+@{segment}
+M=D
+            ").as_str());
+        }
+        self.contents.push_str("
+@R14
+A=M
+0;JMP
+        ");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOUNDARIES: [i32; 9] = [-32768, -32767, -2, -1, 0, 1, 2, 32766, 32767];
+
+    // Pushes any 16-bit value, as push constant only takes 0..=32767.
+    fn push_value(writer: &mut CodeWriter, value: i32) {
+        let push_constant = |value: i32| parser::VmCommand::Push(parser::Segment::Constant, value as u16);
+        if value >= 0 {
+            writer.writePushPop(&push_constant(value));
+        } else if value == -32768 {
+            writer.writePushPop(&push_constant(32767));
+            writer.writeArithmetic(parser::ArithmeticOp::Neg);
+            writer.writePushPop(&push_constant(1));
+            writer.writeArithmetic(parser::ArithmeticOp::Sub);
+        } else {
+            writer.writePushPop(&push_constant(-value));
+            writer.writeArithmetic(parser::ArithmeticOp::Neg);
+        }
+    }
+
+    // Translates `x y command`, runs it and returns the value left on the stack.
+    fn compare(x: i32, y: i32, op: parser::ArithmeticOp, shared: bool, safe: bool) -> i16 {
+        let mut writer = CodeWriter::create(String::from("-"));
+        if shared {
+            writer.enableSharedComparison();
+        }
+        if safe {
+            writer.enableSafeComparison();
+        }
+        push_value(&mut writer, x);
+        push_value(&mut writer, y);
+        writer.writeArithmetic(op);
+        let emulator = execute(&writer);
+        assert_eq!(emulator.ram()[0], 257);
+        emulator.ram()[256] as i16
+    }
+
+    // Writes every command of the VM code, as if it came from the current file.
+    fn write_vm(writer: &mut CodeWriter, contents: &str) {
+        let commands = parser::Parser::parse(String::from(contents)).unwrap();
+        for (_, command) in &commands {
+            writer.writeCommand(command);
+        }
+    }
+
+    // Runs the translated code from SP = 256 until it halts.
+    fn execute(writer: &CodeWriter) -> emulator::Emulator {
+        let program = assembler::assemble(&writer.get_contents()).unwrap();
+        let mut emulator = emulator::Emulator::create(&program.words()).unwrap();
+        emulator.ram_mut()[0] = 256;
+        emulator.run_until_halt(10000).unwrap();
+        emulator
+    }
+
+    fn expected(condition: bool) -> i16 {
+        if condition { -1 } else { 0 }
+    }
+
+    #[test]
+    fn safe_comparison_is_correct_for_all_boundaries() {
+        for shared in [false, true] {
+            for x in BOUNDARIES {
+                for y in BOUNDARIES {
+                    assert_eq!(compare(x, y, parser::ArithmeticOp::Gt, shared, true), expected(x > y), "{} gt {}", x, y);
+                    assert_eq!(compare(x, y, parser::ArithmeticOp::Lt, shared, true), expected(x < y), "{} lt {}", x, y);
+                    assert_eq!(compare(x, y, parser::ArithmeticOp::Eq, shared, true), expected(x == y), "{} eq {}", x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fast_comparison_is_correct_without_overflow() {
+        for x in BOUNDARIES {
+            for y in BOUNDARIES {
+                if (-32768..=32767).contains(&(x - y)) {
+                    assert_eq!(compare(x, y, parser::ArithmeticOp::Gt, false, false), expected(x > y), "{} gt {}", x, y);
+                    assert_eq!(compare(x, y, parser::ArithmeticOp::Lt, false, false), expected(x < y), "{} lt {}", x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fast_comparison_is_wrong_on_overflow() {
+        assert_eq!(compare(32767, -2, parser::ArithmeticOp::Gt, false, false), 0);
+        assert_eq!(compare(-32768, 1, parser::ArithmeticOp::Lt, false, false), 0);
+    }
+
+    #[test]
+    fn source_map_ranges_point_at_the_code_of_each_command() {
+        let mut writer = CodeWriter::create(String::from("Main.asm"));
+        writer.enableOptimization();
+        writer.enableSourceMap();
+        writer.enableSourceComments();
+        writer.setFileName("Main.vm");
+        let commands = parser::Parser::parse(String::from("push constant 2\npush constant 3\nadd\npop local 0\npush local 0\nneg\n")).unwrap();
+        // Translated as with -O: the folded constant and the pop become one move.
+        let folded = optimizer::fold_constants(&commands);
+        assert_eq!(folded[0].command, parser::VmCommand::Push(parser::Segment::Constant, 5));
+        writer.setSourceCommands(&folded[0].sources);
+        writer.setSourceCommands(&folded[1].sources);
+        writer.writeMove(parser::Segment::Constant, 5, parser::Segment::Local, 0);
+        for folded in &folded[2..] {
+            writer.setSourceCommands(&folded.sources);
+            writer.writeCommand(&folded.command);
+        }
+        let (code, line_ranges) = writer.render();
+        let lines: Vec<&str> = code.lines().collect();
+        // The move is the code of the pop, so the folded push has none of its own.
+        assert_eq!(line_ranges[0], None);
+        let mut previous_end = 0;
+        for (index, range) in line_ranges.iter().enumerate().skip(1) {
+            let (start, end) = range.unwrap();
+            assert!(previous_end < start && start <= end, "{:?} overlaps the previous command", range);
+            // Comments of the command, and of those merged into it, come right before its first line.
+            assert_eq!(lines[start - 2], writer.source_comment(index));
+            for line in &lines[start - 1..end] {
+                assert!(!line.trim().is_empty() && !line.starts_with("//"), "`{}` is not code", line);
+            }
+            previous_end = end;
+        }
+        assert_eq!(lines[line_ranges[1].unwrap().0 - 3], "// Main.vm:1-3: push constant 2, push constant 3, add");
+        let json = writer.source_map_json(&line_ranges);
+        let asm: Vec<&str> = json.lines()
+            .filter_map(|line| line.split("\"asm\": ").nth(1))
+            .map(|asm| asm.trim_end_matches(',').trim_end_matches('}'))
+            .collect();
+        let expected: Vec<String> = line_ranges.iter()
+            .map(|range| range.map_or(String::from("null"), |(start, end)| format!("[{}, {}]", start, end)))
+            .collect();
+        assert_eq!(asm, expected);
+        assert!(json.contains("{\"source\": \"Main.vm\", \"line\": 1, \"end_line\": 3, \"command\": \"push constant 2, push constant 3, add\", \"asm\": null}"));
+    }
+
+    #[test]
+    fn labels_are_scoped_to_their_function_or_else_their_file() {
+        let mut writer = CodeWriter::create(String::from("-"));
+        writer.setFileName("dir/Main.vm");
+        write_vm(&mut writer, "label LOOP\ngoto LOOP\nfunction Main.loop 0\nlabel LOOP\nif-goto LOOP\n");
+        let code = writer.getCode();
+        let labels: Vec<&str> = code.lines().map(|line| line.trim()).filter(|line| line.contains("LOOP")).collect();
+        assert_eq!(labels, ["(Main$LOOP)", "@Main$LOOP", "(Main.loop$LOOP)", "@Main.loop$LOOP"]);
+    }
+
+    #[test]
+    fn if_goto_jumps_back_while_the_top_of_the_stack_is_not_zero() {
+        let mut writer = CodeWriter::create(String::from("-"));
+        writer.setFileName("Main.vm");
+        // Sums 5 + 4 + 3 + 2 + 1 in temp 1, counting down in temp 0.
+        write_vm(&mut writer, "
+            push constant 5\npop temp 0\npush constant 0\npop temp 1
+            label LOOP
+            push temp 1\npush temp 0\nadd\npop temp 1
+            push temp 0\npush constant 1\nsub\npop temp 0
+            push temp 0\nif-goto LOOP
+        ");
+        let emulator = execute(&writer);
+        assert_eq!((emulator.ram()[5], emulator.ram()[6], emulator.ram()[0]), (0, 15, 256));
+    }
+
+    #[test]
+    fn calls_return_the_value_and_restore_the_frame_of_the_caller() {
+        let mut writer = CodeWriter::create(String::from("-"));
+        writer.setFileName("Main.vm");
+        // Main.outer(3, 4) returns Main.double(3) + 4, with a local variable in between.
+        write_vm(&mut writer, "
+            push constant 3\npush constant 4\ncall Main.outer 2
+            label END\ngoto END
+            function Main.outer 1
+            push argument 0\ncall Main.double 1\npop local 0
+            push local 0\npush argument 1\nadd\nreturn
+            function Main.double 0
+            push argument 0\npush argument 0\nadd\nreturn
+        ");
+        let program = assembler::assemble(&writer.get_contents()).unwrap();
+        let mut emulator = emulator::Emulator::create(&program.words()).unwrap();
+        let segments = [256, 300, 400, 3000, 3010];
+        emulator.ram_mut()[..5].copy_from_slice(&segments);
+        emulator.run_until_halt(10000).unwrap();
+        // The arguments are replaced by the return value, and LCL, ARG, THIS and THAT are as before the call.
+        assert_eq!(emulator.ram()[..5], [257, 300, 400, 3000, 3010]);
+        assert_eq!(emulator.ram()[256], 10);
+    }
+
+    #[test]
+    fn bootstrap_sets_sp_and_calls_sys_init() {
+        let mut writer = CodeWriter::create(String::from("-"));
+        writer.writeInit();
+        writer.setFileName("Sys.vm");
+        write_vm(&mut writer, "function Sys.init 0\npush constant 7\nlabel END\ngoto END\n");
+        let emulator = execute(&writer);
+        // Sys.init runs with its frame of five words above RAM[256], and nothing to return to.
+        assert_eq!(emulator.ram()[..3], [262, 261, 256]);
+        assert_eq!(emulator.ram()[261], 7);
+    }
+}
@@ -0,0 +1,421 @@
+
+use super::code_writer;
+use super::parser;
+use super::statics;
+
+pub const RAM_SIZE: usize = 32768;
+
+#[derive(Debug)]
+pub enum InterpreterError {
+    UnknownLabel(String),
+    UnknownFunction(String),
+}
+
+impl std::fmt::Display for InterpreterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            InterpreterError::UnknownLabel(label) => write!(f, "unknown label `{}`", label),
+            InterpreterError::UnknownFunction(function_name) => write!(f, "unknown function `{}`", function_name),
+        }
+    }
+}
+
+// A command together with everything needed to execute it without looking at its neighbours.
+struct Command {
+    command: parser::VmCommand,
+    // The function the command belongs to, or the file name outside of any function.
+    scope: String,
+    filename_vm: String,
+}
+
+// Executes VM commands directly, keeping SP, the segment pointers and the stack in RAM
+// at the same places as the translated program does, so that both can be compared.
+// The only difference is the return address in a frame, which is a command index here.
+pub struct Interpreter {
+    commands: Vec<Command>,
+    // Mangled labels and function names to command indices.
+    labels: std::collections::HashMap<String, usize>,
+    // Static variables to RAM addresses.
+    statics: std::collections::HashMap<String, usize>,
+    ram: Vec<i16>,
+    pc: usize,
+    call_stack: Vec<String>,
+    // The RAM address of the return address of every frame, the innermost last.
+    return_addresses: Vec<usize>,
+}
+
+impl Interpreter {
+    // Static variables are allocated from RAM[16] in order of first appearance.
+    pub fn create(vm_files: &[parser::VmFile]) -> Interpreter {
+        Self::create_with_statics(vm_files, &statics::allocate(vm_files))
+    }
+    // Same as create, with static variables at the given addresses, e.g. those of the translated program.
+    pub fn create_with_statics(vm_files: &[parser::VmFile], statics: &[statics::StaticVariable]) -> Interpreter {
+        let statics = statics.iter().map(|variable| (variable.symbol.clone(), variable.address)).collect();
+        let mut commands = vec![];
+        for vm_file in vm_files {
+            let filename_vm = code_writer::CodeWriter::get_vm_filename_from_full_path(&vm_file.path);
+            let mut scope = filename_vm.clone();
+            for (_, command) in &vm_file.commands {
+                if let parser::VmCommand::Function(function_name, _) = command {
+                    scope = function_name.clone();
+                }
+                commands.push(Command {
+                    command: command.clone(),
+                    scope: scope.clone(),
+                    filename_vm: filename_vm.clone(),
+                });
+            }
+        }
+        let mut labels = std::collections::HashMap::new();
+        for (index, command) in commands.iter().enumerate() {
+            match &command.command {
+                parser::VmCommand::Label(label) => {
+                    labels.insert(format!("{}${}", command.scope, label), index);
+                },
+                parser::VmCommand::Function(function_name, _) => {
+                    labels.insert(function_name.clone(), index);
+                },
+                _ => {},
+            }
+        }
+        Interpreter {
+            commands,
+            labels,
+            statics,
+            ram: vec![0; RAM_SIZE],
+            pc: 0,
+            call_stack: vec![],
+            return_addresses: vec![],
+        }
+    }
+    // Same as the bootstrap code of the translator: SP=256, then call Sys.init.
+    pub fn bootstrap(&mut self) -> Result<(), InterpreterError> {
+        self.ram[0] = 256;
+        self.call("Sys.init", 0, self.commands.len())
+    }
+    pub fn ram(&self) -> &[i16] {
+        &self.ram
+    }
+    pub fn ram_mut(&mut self) -> &mut [i16] {
+        &mut self.ram
+    }
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+    // The values between the base of the stack and SP.
+    pub fn stack(&self) -> &[i16] {
+        let sp = (self.ram[0] as u16 as usize).clamp(256, RAM_SIZE);
+        &self.ram[256..sp]
+    }
+    // Static variables with their RAM addresses, sorted by address.
+    pub fn statics(&self) -> Vec<(String, usize)> {
+        let mut statics: Vec<(String, usize)> = self.statics.iter().map(|(symbol, address)| (symbol.clone(), *address)).collect();
+        statics.sort_by_key(|(_, address)| *address);
+        statics
+    }
+    // Names of the functions being executed, the innermost last.
+    pub fn call_stack(&self) -> &[String] {
+        &self.call_stack
+    }
+    // The addresses where the translated program, once halted, holds other values than the interpreter:
+    // SP, LCL, ARG, THIS, THAT, temp, statics and the stack up to SP are compared, except the return address
+    // of every frame still on the stack, which is a command index here and a ROM address there.
+    // R13..R15 are scratch registers of the translated code, and nothing above SP is kept.
+    // Returns every address that differs, with the value of the interpreter then the value of the translated program.
+    pub fn compare_ram(&self, ram: &[u16]) -> Vec<(usize, i16, i16)> {
+        let sp = (self.ram[0] as u16 as usize).clamp(256, RAM_SIZE);
+        let mut addresses: Vec<usize> = (0..13).collect();
+        addresses.extend(self.statics().into_iter().map(|(_, address)| address));
+        addresses.extend((256..sp).filter(|address| !self.return_addresses.contains(address)));
+        addresses.into_iter()
+            .filter(|address| self.ram[*address] != ram[*address] as i16)
+            .map(|address| (address, self.ram[address], ram[address] as i16))
+            .collect()
+    }
+    pub fn current_command(&self) -> Option<String> {
+        self.commands.get(self.pc).map(|command| command.command.to_string())
+    }
+    // The program is halted when it runs past its last command or jumps to itself
+    // with nothing but labels in between, as in `label END` `goto END`.
+    pub fn is_halted(&self) -> bool {
+        match self.commands.get(self.pc) {
+            None => true,
+            Some(command) => match &command.command {
+                parser::VmCommand::Goto(label) => {
+                    let label = format!("{}${}", command.scope, label);
+                    match self.labels.get(&label) {
+                        Some(target) if *target <= self.pc => self.commands[*target..self.pc].iter()
+                            .all(|c| matches!(c.command, parser::VmCommand::Label(_))),
+                        _ => false,
+                    }
+                },
+                _ => false,
+            },
+        }
+    }
+    // Executes up to `steps` commands, stopping early when halted. Returns the number executed.
+    pub fn run(&mut self, steps: usize) -> Result<usize, InterpreterError> {
+        let mut executed = 0;
+        while executed < steps && !self.is_halted() {
+            self.step()?;
+            executed += 1;
+        }
+        Ok(executed)
+    }
+    // Executes a single command.
+    pub fn step(&mut self) -> Result<(), InterpreterError> {
+        let command = &self.commands[self.pc];
+        let mut next = self.pc + 1;
+        match &command.command {
+            parser::VmCommand::Arithmetic(op) => {
+                let op = *op;
+                self.arithmetic(op);
+            },
+            parser::VmCommand::Push(segment, index) => {
+                let value = match self.address(*segment, *index, &command.filename_vm) {
+                    Some(address) => self.ram[address],
+                    None => *index as i16,
+                };
+                self.push(value);
+            },
+            parser::VmCommand::Pop(segment, index) => {
+                let address = self.address(*segment, *index, &command.filename_vm);
+                let value = self.pop();
+                match address {
+                    Some(address) => self.ram[address] = value,
+                    None => unreachable!("pop constant is rejected by the parser"),
+                }
+            },
+            parser::VmCommand::Label(_) => {},
+            parser::VmCommand::Goto(label) => {
+                next = self.label(&command.scope, label)?;
+            },
+            parser::VmCommand::If(label) => {
+                let target = self.label(&command.scope, label)?;
+                if self.pop() != 0 {
+                    next = target;
+                }
+            },
+            parser::VmCommand::Function(_, num_locals) => {
+                let num_locals = *num_locals;
+                for _ in 0..num_locals {
+                    self.push(0);
+                }
+            },
+            parser::VmCommand::Call(function_name, num_args) => {
+                let function_name = function_name.clone();
+                let num_args = *num_args;
+                self.call(&function_name, num_args, next)?;
+                return Ok(());
+            },
+            parser::VmCommand::Return => {
+                // Returning from no call, e.g. from a function tested without Sys.init, ends the program.
+                let returned_from_call = !self.call_stack.is_empty();
+                let return_address = self.ret();
+                next = if returned_from_call { return_address } else { self.commands.len() };
+            },
+        }
+        self.pc = next;
+        Ok(())
+    }
+    fn label(&self, scope: &str, label: &str) -> Result<usize, InterpreterError> {
+        let label = format!("{}${}", scope, label);
+        self.labels.get(&label).copied().ok_or(InterpreterError::UnknownLabel(label))
+    }
+    fn register(&self, address: usize) -> usize {
+        self.ram[address] as u16 as usize
+    }
+    fn push(&mut self, value: i16) {
+        let sp = self.register(0);
+        self.ram[sp % RAM_SIZE] = value;
+        self.ram[0] = self.ram[0].wrapping_add(1);
+    }
+    fn pop(&mut self) -> i16 {
+        self.ram[0] = self.ram[0].wrapping_sub(1);
+        self.ram[self.register(0) % RAM_SIZE]
+    }
+    // The RAM address of a segment entry, or None for constant which is not stored in memory.
+    fn address(&self, segment: parser::Segment, index: u16, filename_vm: &str) -> Option<usize> {
+        let index = index as usize;
+        let address = match segment {
+            parser::Segment::Local => self.register(1) + index,
+            parser::Segment::Argument => self.register(2) + index,
+            parser::Segment::This => self.register(3) + index,
+            parser::Segment::That => self.register(4) + index,
+            parser::Segment::Pointer => 3 + index,
+            parser::Segment::Temp => 5 + index,
+            parser::Segment::Static => *self.statics.get(&format!("{}.{}", filename_vm, index)).unwrap(),
+            parser::Segment::Constant => return None,
+        };
+        Some(address % RAM_SIZE)
+    }
+    // Unlike the translated code, gt and lt compare the values themselves, so they never overflow.
+    fn arithmetic(&mut self, op: parser::ArithmeticOp) {
+        let from_bool = |b: bool| if b { -1 } else { 0 };
+        let result = match op {
+            parser::ArithmeticOp::Neg => self.pop().wrapping_neg(),
+            parser::ArithmeticOp::Not => !self.pop(),
+            _ => {
+                let y = self.pop();
+                let x = self.pop();
+                match op {
+                    parser::ArithmeticOp::Add => x.wrapping_add(y),
+                    parser::ArithmeticOp::Sub => x.wrapping_sub(y),
+                    parser::ArithmeticOp::Eq => from_bool(x == y),
+                    parser::ArithmeticOp::Gt => from_bool(x > y),
+                    parser::ArithmeticOp::Lt => from_bool(x < y),
+                    parser::ArithmeticOp::And => x & y,
+                    parser::ArithmeticOp::Or => x | y,
+                    parser::ArithmeticOp::Neg | parser::ArithmeticOp::Not => unreachable!(),
+                }
+            },
+        };
+        self.push(result);
+    }
+    fn call(&mut self, function_name: &str, num_args: u16, return_address: usize) -> Result<(), InterpreterError> {
+        let target = self.labels.get(function_name).copied()
+            .ok_or(InterpreterError::UnknownFunction(String::from(function_name)))?;
+        self.return_addresses.push(self.register(0) % RAM_SIZE);
+        self.push(return_address as i16);
+        for segment in 1..=4 {
+            self.push(self.ram[segment]);
+        }
+        self.ram[2] = self.ram[0].wrapping_sub(5 + num_args as i16);
+        self.ram[1] = self.ram[0];
+        self.call_stack.push(String::from(function_name));
+        self.pc = target;
+        Ok(())
+    }
+    // Addresses wrap around as they do in the translated code, whatever the frame.
+    fn ret(&mut self) -> usize {
+        let frame = self.register(1);
+        let saved = |offset: usize| (frame + RAM_SIZE - offset) % RAM_SIZE;
+        let return_address = self.ram[saved(5)] as u16 as usize;
+        let value = self.pop();
+        let arg = self.register(2) % RAM_SIZE;
+        self.ram[arg] = value;
+        self.ram[0] = self.ram[2].wrapping_add(1);
+        for segment in 1..=4 {
+            self.ram[segment] = self.ram[saved(5 - segment)];
+        }
+        self.call_stack.pop();
+        self.return_addresses.pop();
+        return_address
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vm_file(path: &str, contents: &str) -> parser::VmFile {
+        parser::VmFile {
+            path: String::from(path),
+            commands: parser::Parser::parse(String::from(contents)).unwrap(),
+        }
+    }
+
+    // Translates the files, with bootstrap when asked to, and assembles them.
+    fn translate(vm_files: &[parser::VmFile], bootstrap: bool) -> assembler::Program {
+        let mut writer = code_writer::CodeWriter::create(String::from("-"));
+        if bootstrap {
+            writer.writeInit();
+        }
+        for vm_file in vm_files {
+            writer.setFileName(&vm_file.path);
+            for (_, command) in &vm_file.commands {
+                writer.writeCommand(command);
+            }
+        }
+        assembler::assemble(&writer.getCode()).unwrap()
+    }
+
+    // Creates an interpreter with the static addresses of the translated program.
+    fn create_as_assembled(vm_files: &[parser::VmFile], bootstrap: bool) -> Interpreter {
+        let program = translate(vm_files, bootstrap);
+        Interpreter::create_with_statics(vm_files, &statics::allocate_as_assembled(vm_files, &program.symbol_table))
+    }
+
+    // Translates the files with bootstrap and runs them on the emulator, returning the final RAM.
+    fn run_translated(vm_files: &[parser::VmFile]) -> Vec<u16> {
+        let program = translate(vm_files, true);
+        let mut emulator = emulator::Emulator::create(&program.words()).unwrap();
+        emulator.run_until_halt(100_000).unwrap();
+        emulator.ram().to_vec()
+    }
+
+    #[test]
+    fn calls_and_statics_end_with_the_same_ram_as_the_translated_program() {
+        let vm_files = [
+            vm_file("Main.vm", "function Main.double 0\npush argument 0\npush argument 0\nadd\npop static 0\npush static 0\nreturn\n"),
+            vm_file("Sys.vm", "function Sys.init 1\npush constant 21\ncall Main.double 1\npop local 0\npush local 0\npush constant 1\nsub\nlabel END\ngoto END\n"),
+        ];
+        let mut interpreter = create_as_assembled(&vm_files, true);
+        interpreter.bootstrap().unwrap();
+        interpreter.run(1000).unwrap();
+        assert!(interpreter.is_halted());
+        assert_eq!(interpreter.stack().last(), Some(&41));
+        assert_eq!(interpreter.statics(), vec![(String::from("Main.0"), 16)]);
+        assert_eq!(interpreter.compare_ram(&run_translated(&vm_files)), vec![]);
+    }
+
+    #[test]
+    fn statics_follow_the_variables_of_undefined_functions() {
+        let vm_files = [
+            vm_file("Main.vm", "function Main.main 0\npush constant 6\npush constant 7\ncall Math.multiply 2\npop static 0\npush static 0\npop static 1\nreturn\n"),
+            vm_file("Sys.vm", "function Sys.init 0\ncall Main.main 0\nlabel END\ngoto END\n"),
+        ];
+        // Math.multiply is not translated, so the Assembler makes it the variable at RAM[16].
+        let interpreter = create_as_assembled(&vm_files, true);
+        assert_eq!(interpreter.statics(), vec![(String::from("Main.0"), 17), (String::from("Main.1"), 18)]);
+    }
+
+    #[test]
+    fn statics_that_reach_the_stack_are_spilled() {
+        let pops: String = (0..241).map(|index| format!("pop static {}\n", index)).collect();
+        let vm_files = [vm_file("Main.vm", &format!("function Main.main 0\n{}return\n", pops))];
+        let program = translate(&vm_files, false);
+        let statics = statics::allocate_as_assembled(&vm_files, &program.symbol_table);
+        assert!(statics::spilled(&statics[..240]).is_none());
+        let variable = statics::spilled(&statics).unwrap();
+        assert_eq!((variable.symbol.as_str(), variable.line, variable.address), ("Main.240", 242, statics::STACK_BASE));
+    }
+
+    #[test]
+    fn compare_ram_reports_differences_below_sp() {
+        let vm_files = [vm_file("Sys.vm", "function Sys.init 0\npush constant 7\nlabel END\ngoto END\n")];
+        let mut interpreter = create_as_assembled(&vm_files, true);
+        interpreter.bootstrap().unwrap();
+        interpreter.run(1000).unwrap();
+        let mut ram = run_translated(&vm_files);
+        ram[interpreter.ram()[0] as usize - 1] = 8;
+        ram[interpreter.ram()[0] as usize] = 9;
+        assert_eq!(interpreter.compare_ram(&ram), vec![(261, 7, 8)]);
+    }
+
+    #[test]
+    fn return_without_call_ends_the_program() {
+        let vm_files = [vm_file("Ret.vm", "push constant 1\nreturn\npush constant 2\n")];
+        let mut interpreter = Interpreter::create(&vm_files);
+        interpreter.ram_mut()[0] = 256;
+        assert_eq!(interpreter.run(1000).ok(), Some(2));
+        assert!(interpreter.is_halted());
+        // ARG is 0, so the value is returned to RAM[0] and SP becomes ARG+1.
+        assert_eq!(interpreter.ram()[0], 1);
+    }
+
+    #[test]
+    fn return_from_a_frame_set_up_by_hand_restores_the_segments() {
+        let vm_files = [vm_file("SimpleFunction.vm", "function SimpleFunction.test 2\npush local 0\npush local 1\nadd\nnot\npush argument 0\nadd\npush argument 1\nsub\nreturn\n")];
+        let mut interpreter = Interpreter::create(&vm_files);
+        // As SimpleFunction.tst does: a frame at 310..317 and two arguments below it.
+        for (address, value) in [(0, 317), (1, 317), (2, 310), (3, 3000), (4, 4000), (310, 1234), (311, 37), (312, 9), (313, 305), (314, 300), (315, 3010), (316, 4010)] {
+            interpreter.ram_mut()[address] = value;
+        }
+        interpreter.run(1000).unwrap();
+        assert!(interpreter.is_halted());
+        assert_eq!(&interpreter.ram()[0..5], &[311, 305, 300, 3010, 4010]);
+        assert_eq!(interpreter.ram()[310], 1196);
+    }
+}
@@ -1,19 +1,106 @@
+#![allow(non_snake_case, non_camel_case_types)]
+
 fn main() {
     let arguments: Vec<String> = std::env::args().collect();
-    if arguments.len() < 2 {
-        panic!("no argument !");
-    } else {
-        let path: &str = arguments.get(1).unwrap();
-        let virtual_machine = VirtualMachine::create(String::from(path));
-        virtual_machine.run();
+    let mut path = None;
+    let mut output = None;
+    let mut no_bootstrap = false;
+    let mut shared_comparison = false;
+    let mut safe_comparison = false;
+    let mut optimization = false;
+    let mut static_report = false;
+    let mut source_map = false;
+    let mut source_comments = false;
+    let mut hack = false;
+    let mut keep_asm = false;
+    let mut interpret = false;
+    let mut compare = false;
+    let mut max_steps = 1_000_000;
+    let mut index = 1;
+    while index < arguments.len() {
+        let argument = arguments.get(index).unwrap();
+        if argument == "-o" {
+            index += 1;
+            output = Some(arguments.get(index).expect("no output path after -o !").clone());
+        } else if argument == "--no-bootstrap" {
+            no_bootstrap = true;
+        } else if argument == "--shared-comparison" {
+            shared_comparison = true;
+        } else if argument == "--safe-comparison" {
+            safe_comparison = true;
+        } else if argument == "-O" {
+            optimization = true;
+        } else if argument == "--statics" {
+            static_report = true;
+        } else if argument == "--source-map" {
+            source_map = true;
+        } else if argument == "--source-comments" {
+            source_comments = true;
+        } else if argument == "--hack" {
+            hack = true;
+        } else if argument == "--keep-asm" {
+            keep_asm = true;
+        } else if argument == "--run" {
+            interpret = true;
+        } else if argument == "--compare" {
+            compare = true;
+        } else if argument == "--max-steps" {
+            index += 1;
+            max_steps = arguments.get(index).expect("no number after --max-steps !").parse().unwrap();
+        } else {
+            path = Some(argument.clone());
+        }
+        index += 1;
+    }
+    match path {
+        None => panic!("no argument !"),
+        Some(path) => {
+            let (output, asm_output) = VirtualMachine::output_paths(&path, output, hack);
+            let mut virtual_machine = VirtualMachine::create(path);
+            if no_bootstrap {
+                virtual_machine.bootstrap = false;
+            }
+            virtual_machine.shared_comparison = shared_comparison;
+            virtual_machine.safe_comparison = safe_comparison;
+            virtual_machine.optimization = optimization;
+            virtual_machine.static_report = static_report;
+            virtual_machine.source_map = source_map;
+            virtual_machine.source_comments = source_comments;
+            if compare {
+                virtual_machine.compare(max_steps);
+            } else if interpret {
+                virtual_machine.interpret(max_steps);
+            } else if hack {
+                virtual_machine.build(output, keep_asm.then_some(asm_output));
+            } else {
+                virtual_machine.run(output);
+            }
+        }
     }
 }
 
 mod parser;
 mod code_writer;
+mod interpreter;
+mod optimizer;
+mod statics;
 
 struct VirtualMachine {
     path_of_files: Vec<String>,
+    // Whether to start the program by setting up SP and calling Sys.init.
+    bootstrap: bool,
+    // Whether eq, gt and lt call shared routines rather than being written inline.
+    shared_comparison: bool,
+    // Whether gt and lt are correct even when x-y overflows, at the cost of more instructions.
+    safe_comparison: bool,
+    // Whether constant arithmetic is folded, push followed by pop is written as a move, and redundant instructions are removed.
+    optimization: bool,
+    // Whether to print the address of every static variable.
+    static_report: bool,
+    // Whether to write `<output>.map`, mapping every VM command to the lines of its code.
+    source_map: bool,
+    // Whether to write every VM command as a comment before its code.
+    source_comments: bool,
 }
 
 impl VirtualMachine {
@@ -31,6 +118,8 @@ impl VirtualMachine {
                     // Do nothing
                 }
             }
+            // read_dir does not guarantee any order, so sort to make the output reproducible.
+            path_of_files.sort();
         } else {
             if Self::validate_file_extension(&path) {
                 path_of_files.push(path);
@@ -40,33 +129,257 @@ impl VirtualMachine {
         }
         VirtualMachine {
             path_of_files,
+            bootstrap: is_directory,
+            shared_comparison: false,
+            safe_comparison: false,
+            optimization: false,
+            static_report: false,
+            source_map: false,
+            source_comments: false,
         }
     }
-    fn run(&self) {
-        let mut writer = code_writer::CodeWriter::create(String::from("output.asm"));
-        for path_of_file in &self.path_of_files {
-            let contents = std::fs::read_to_string(path_of_file).unwrap();
-            let mut parser = parser::Parser::create(contents);
-            let filename_vm = writer.setFileName(path_of_file);
-            while parser.hasMoreCommands() {
-                match parser.commandType() {
-                    parser::CommandType::C_ARITHMETIC => {
-                        writer.writeArithmetic(parser.arg0().as_str());
-                    },
-                    parser::CommandType::C_PUSH => {
-                        writer.writePushPop(parser::CommandType::C_PUSH, parser.arg1().as_str(), parser.arg2());
+    fn run(&self, output: String) {
+        let (writer, _) = self.translate_files(output);
+        writer.close().unwrap();
+    }
+    // Translates, then assembles the translated code in memory and writes the `.hack` file.
+    // The translated code is also written to asm_output when given, with its source map if any.
+    fn build(&self, output: String, asm_output: Option<String>) {
+        let asm_path = asm_output.clone().unwrap_or_else(|| String::from("-"));
+        let (writer, program) = self.translate_files(asm_path);
+        if asm_output.is_some() {
+            writer.close().unwrap();
+        }
+        if output == "-" {
+            println!("{}", program.binary());
+        } else {
+            std::fs::write(output, program.binary()).unwrap();
+        }
+    }
+    // Parses, translates and assembles every file, reporting what the options saved.
+    fn translate_files(&self, output: String) -> (code_writer::CodeWriter, assembler::Program) {
+        let vm_files = self.parse_files();
+        let writer = self.translate(&vm_files, output.clone(), self.optimization);
+        let program = Self::assemble(&writer.getCode(), &output);
+        self.allocate_statics(&vm_files, &program);
+        if self.shared_comparison {
+            eprintln!("shared comparison routines saved {} instructions", writer.getSharedComparisonSaving());
+        }
+        if self.optimization {
+            let unoptimized = self.translate(&vm_files, output, false).getInstructionCount();
+            let optimized = writer.getInstructionCount();
+            eprintln!("optimization reduced {} instructions to {} ({} saved)", unoptimized, optimized, unoptimized - optimized);
+        }
+        (writer, program)
+    }
+    fn translate(&self, vm_files: &[parser::VmFile], output: String, optimization: bool) -> code_writer::CodeWriter {
+        let mut writer = code_writer::CodeWriter::create(output);
+        if self.shared_comparison {
+            writer.enableSharedComparison();
+        }
+        if self.safe_comparison {
+            writer.enableSafeComparison();
+        }
+        if optimization {
+            writer.enableOptimization();
+        }
+        if self.source_map {
+            writer.enableSourceMap();
+        }
+        if self.source_comments {
+            writer.enableSourceComments();
+        }
+        if self.bootstrap {
+            writer.writeInit();
+        }
+        for vm_file in vm_files {
+            writer.setFileName(&vm_file.path);
+            if !optimization {
+                for (line, command) in &vm_file.commands {
+                    writer.setSourceCommand(*line, command);
+                    writer.writeCommand(command);
+                }
+                continue;
+            }
+            let commands = optimizer::fold_constants(&vm_file.commands);
+            let mut index = 0;
+            while index < commands.len() {
+                match (&commands[index].command, commands.get(index + 1).map(|folded| &folded.command)) {
+                    (parser::VmCommand::Push(source, source_index), Some(parser::VmCommand::Pop(target, target_index))) => {
+                        writer.setSourceCommands(&commands[index].sources);
+                        writer.setSourceCommands(&commands[index + 1].sources);
+                        writer.writeMove(*source, *source_index, *target, *target_index);
+                        index += 2;
                     },
-                    parser::CommandType::C_POP => {
-                        writer.writePushPop(parser::CommandType::C_POP, parser.arg1().as_str(), parser.arg2());
+                    (command, _) => {
+                        writer.setSourceCommands(&commands[index].sources);
+                        writer.writeCommand(command);
+                        index += 1;
                     },
-                    _ => {
-                        panic!("invalid command type !");
+                }
+            }
+        }
+        writer
+    }
+    // Executes the program with the interpreter instead of translating it, then prints the state of the machine.
+    fn interpret(&self, max_steps: usize) {
+        let vm_files = self.parse_files();
+        let (interpreter, result) = self.start_interpreter(interpreter::Interpreter::create(&vm_files), max_steps);
+        match result {
+            Ok(steps) => println!("executed {} commands{}", steps, if interpreter.is_halted() { ", halted" } else { "" }),
+            Err(error) => {
+                eprintln!("error: {} at command {}", error, interpreter.pc());
+                std::process::exit(1);
+            },
+        }
+        if let Some(command) = interpreter.current_command() {
+            println!("next command: {} (in {})", command, interpreter.call_stack().join(" > "));
+        }
+        println!("stack: {:?}", interpreter.stack());
+        for (address, value) in interpreter.ram().iter().take(16).enumerate() {
+            println!("RAM[{}]: {}", address, value);
+        }
+        for (symbol, address) in interpreter.statics() {
+            println!("RAM[{}]: {} ({})", address, interpreter.ram()[address], symbol);
+        }
+    }
+    // Without bootstrap, only SP is set up, to 256.
+    fn start_interpreter(&self, mut interpreter: interpreter::Interpreter, max_steps: usize) -> (interpreter::Interpreter, Result<usize, interpreter::InterpreterError>) {
+        let result = if self.bootstrap {
+            interpreter.bootstrap().and_then(|_| interpreter.run(max_steps))
+        } else {
+            interpreter.ram_mut()[0] = 256;
+            interpreter.run(max_steps)
+        };
+        (interpreter, result)
+    }
+    // Runs the program with the interpreter, then translates it and runs it on the emulator, and reports
+    // every address where the final RAM differs. Each runs at most max_steps commands or instructions.
+    // Static variables are interpreted at the addresses of the translated program, so that both can be compared.
+    fn compare(&self, max_steps: usize) {
+        let vm_files = self.parse_files();
+        let code = self.translate(&vm_files, String::from("-"), self.optimization).getCode();
+        let program = Self::assemble(&code, "-");
+        let statics = self.allocate_statics(&vm_files, &program);
+        let interpreter = interpreter::Interpreter::create_with_statics(&vm_files, &statics);
+        let (interpreter, result) = self.start_interpreter(interpreter, max_steps);
+        if let Err(error) = result {
+            eprintln!("error: {} at command {}", error, interpreter.pc());
+            std::process::exit(1);
+        }
+        let mut emulator = match emulator::Emulator::create(&program.words()) {
+            Ok(emulator) => emulator,
+            Err(error) => {
+                eprintln!("error: translated program: {}", error);
+                std::process::exit(1);
+            },
+        };
+        if !self.bootstrap {
+            emulator.ram_mut()[0] = 256;
+        }
+        if let Err(error) = emulator.run_until_halt(max_steps) {
+            eprintln!("error: translated program: {}", error);
+            std::process::exit(1);
+        }
+        let differences = interpreter.compare_ram(emulator.ram());
+        for (address, interpreted, translated) in &differences {
+            println!("RAM[{}]: {} interpreted, {} translated", address, interpreted, translated);
+        }
+        if differences.is_empty() {
+            println!("the interpreted and translated programs end with the same RAM");
+        } else {
+            eprintln!("error: the final RAM differs at {} address(es)", differences.len());
+            std::process::exit(1);
+        }
+    }
+    // Parses every file, reporting all errors and exiting if there is any.
+    fn parse_files(&self) -> Vec<parser::VmFile> {
+        let mut vm_files = vec![];
+        let mut error_count = 0;
+        for path_of_file in &self.path_of_files {
+            let contents = std::fs::read_to_string(path_of_file).unwrap();
+            match parser::Parser::parse(contents) {
+                Ok(commands) => vm_files.push(parser::VmFile {
+                    path: path_of_file.clone(),
+                    commands,
+                }),
+                Err(errors) => {
+                    for diagnostic in &errors {
+                        eprintln!("{}:{}: error: {}", path_of_file, diagnostic.line, diagnostic.error);
                     }
+                    error_count += errors.len();
+                },
+            }
+        }
+        if error_count > 0 {
+            eprintln!("error: could not translate due to {} previous error(s)", error_count);
+            std::process::exit(1);
+        }
+        // The Assembler would make an undefined Sys.init a variable, and the bootstrap code would jump to its address.
+        if self.bootstrap && !Self::defines_function(&vm_files, "Sys.init") {
+            eprintln!("error: the bootstrap code calls `Sys.init`, which is not defined by any file; define it or use --no-bootstrap");
+            std::process::exit(1);
+        }
+        vm_files
+    }
+    // Whether any file declares the function, rather than only calling it.
+    fn defines_function(vm_files: &[parser::VmFile], name: &str) -> bool {
+        vm_files.iter()
+            .flat_map(|vm_file| &vm_file.commands)
+            .any(|(_, command)| matches!(command, parser::VmCommand::Function(function_name, _) if function_name == name))
+    }
+    // Assembles the translated code, reporting all errors against asm_path and exiting if there is any.
+    fn assemble(code: &str, asm_path: &str) -> assembler::Program {
+        match assembler::assemble(code) {
+            Ok(program) => program,
+            Err(diagnostics) => {
+                for diagnostic in &diagnostics {
+                    eprintln!("{}", diagnostic.render(asm_path, code));
                 }
-                parser.advance();
+                eprintln!("error: could not assemble the translated code due to {} previous error(s)", diagnostics.len());
+                std::process::exit(1);
+            },
+        }
+    }
+    // Finds the addresses of the static variables in the assembled translated code, prints them when asked to,
+    // and exits if they do not fit below the stack.
+    fn allocate_statics(&self, vm_files: &[parser::VmFile], program: &assembler::Program) -> Vec<statics::StaticVariable> {
+        let statics = statics::allocate_as_assembled(vm_files, &program.symbol_table);
+        if self.static_report {
+            for variable in &statics {
+                eprintln!("RAM[{}]: {} (static {} of {})", variable.address, variable.symbol, variable.index, variable.path);
             }
         }
-        writer.close().unwrap();
+        if let Some(variable) = statics::spilled(&statics) {
+            eprintln!("{}:{}: error: static variable `{}` would be allocated at RAM[{}], where the stack begins", variable.path, variable.line, variable.symbol, variable.address);
+            let variables = program.symbol_table.entries(assembler::symbol_table::SymbolKind::Variable).len();
+            eprintln!("error: {} variables, of which {} static, do not fit in RAM[{}..{}]", variables, statics.len(), statics::STATIC_BASE, statics::STACK_BASE);
+            std::process::exit(1);
+        }
+        statics
+    }
+    // The output path, and where --keep-asm writes the assembly.
+    // `Dir/` becomes `Dir/Dir.asm` and `File.vm` becomes `File.asm`, or `.hack` with `--hack`, unless `-o` says otherwise.
+    fn output_paths(path: &str, output: Option<String>, hack: bool) -> (String, String) {
+        let extension = if hack { "hack" } else { "asm" };
+        let output = output.unwrap_or_else(|| Self::default_output_path(path, extension));
+        // The intermediate assembly is kept next to the `.hack` file.
+        let asm_output = if output == "-" {
+            Self::default_output_path(path, "asm")
+        } else {
+            std::path::Path::new(&output).with_extension("asm").into_os_string().into_string().unwrap()
+        };
+        (output, asm_output)
+    }
+    fn default_output_path(path: &str, extension: &str) -> String {
+        let std_path = std::path::Path::new(path);
+        let output = if std_path.is_dir() {
+            let name = std_path.canonicalize().unwrap().file_name().unwrap().to_os_string();
+            std_path.join(name).with_extension(extension)
+        } else {
+            std_path.with_extension(extension)
+        };
+        output.into_os_string().into_string().unwrap()
     }
     fn validate_file_extension(filename: &String) -> bool {
         let mut result = false;
@@ -79,4 +392,63 @@ impl VirtualMachine {
         }
         result
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vm_file(path: &str, contents: &str) -> parser::VmFile {
+        parser::VmFile {
+            path: String::from(path),
+            commands: parser::Parser::parse(String::from(contents)).unwrap(),
+        }
+    }
+
+    #[test]
+    fn sys_init_must_be_defined_rather_than_called() {
+        let main = vm_file("Main.vm", "function Main.main 0\ncall Sys.init 0\nreturn\n");
+        let sys = vm_file("Sys.vm", "function Sys.init 0\ncall Main.main 0\nreturn\n");
+        assert!(!VirtualMachine::defines_function(&[main], "Sys.init"));
+        assert!(VirtualMachine::defines_function(&[sys], "Sys.init"));
+    }
+
+    #[test]
+    fn outputs_are_named_after_the_file_or_directory() {
+        let directory = std::env::temp_dir().join(format!("vm-output-{}", std::process::id())).join("Prog");
+        std::fs::create_dir_all(&directory).unwrap();
+        let directory = directory.into_os_string().into_string().unwrap();
+        assert_eq!(VirtualMachine::default_output_path(&format!("{}/", directory), "asm"), format!("{}/Prog.asm", directory));
+        assert_eq!(VirtualMachine::default_output_path(&directory, "asm"), format!("{}/Prog.asm", directory));
+        assert_eq!(VirtualMachine::default_output_path("dir/Main.vm", "asm"), "dir/Main.asm");
+        std::fs::remove_dir_all(std::path::Path::new(&directory).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn output_goes_where_o_says() {
+        let paths = |output: &str| VirtualMachine::output_paths("dir/Main.vm", Some(String::from(output)), false);
+        assert_eq!(paths("out/Other.asm"), (String::from("out/Other.asm"), String::from("out/Other.asm")));
+        // `-o -` writes to standard output.
+        assert_eq!(paths("-"), (String::from("-"), String::from("dir/Main.asm")));
+    }
+
+    #[test]
+    fn hack_keeps_the_assembly_next_to_the_binary() {
+        assert_eq!(VirtualMachine::output_paths("dir/Main.vm", None, true), (String::from("dir/Main.hack"), String::from("dir/Main.asm")));
+        let paths = VirtualMachine::output_paths("dir/Main.vm", Some(String::from("out/Other.hack")), true);
+        assert_eq!(paths, (String::from("out/Other.hack"), String::from("out/Other.asm")));
+    }
+
+    #[test]
+    fn build_writes_what_the_assembler_makes_of_the_kept_assembly() {
+        let directory = std::env::temp_dir().join(format!("vm-build-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = |name: &str| directory.join(name).into_os_string().into_string().unwrap();
+        std::fs::write(path("Main.vm"), "push constant 2\npush constant 3\nadd\npop static 0\n").unwrap();
+        let virtual_machine = VirtualMachine::create(path("Main.vm"));
+        virtual_machine.build(path("Main.hack"), Some(path("Main.asm")));
+        let program = assembler::assemble(&std::fs::read_to_string(path("Main.asm")).unwrap()).unwrap();
+        assert_eq!(std::fs::read_to_string(path("Main.hack")).unwrap(), program.binary());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,200 @@
+use super::parser::{ArithmeticOp, Segment, VmCommand};
+
+// A command left by fold_constants, with the source commands it stands for and their lines.
+// A command that was not folded stands for itself.
+#[derive(Clone, PartialEq)]
+pub struct FoldedCommand {
+    pub command: VmCommand,
+    pub sources: Vec<(usize, VmCommand)>,
+}
+
+// Replaces arithmetic on constants by the constant it computes, e.g. `push constant 1, push constant 2, add`
+// becomes `push constant 3`. Only results that `push constant` can express, 0..=32767, are folded.
+pub fn fold_constants(commands: &[(usize, VmCommand)]) -> Vec<FoldedCommand> {
+    let mut folded: Vec<FoldedCommand> = vec![];
+    for (line, command) in commands {
+        folded.push(FoldedCommand {
+            command: command.clone(),
+            sources: vec![(*line, command.clone())],
+        });
+        // Folding may leave new constants on top, e.g. `push constant 1, push constant 2, add, push constant 3, add`.
+        while let Some((count, value)) = fold_last(&folded) {
+            let sources = folded.split_off(folded.len() - count).into_iter().flat_map(|folded| folded.sources).collect();
+            folded.push(FoldedCommand {
+                command: VmCommand::Push(Segment::Constant, value),
+                sources,
+            });
+        }
+    }
+    folded
+}
+
+// The number of commands at the end that compute a constant, and the constant.
+fn fold_last(commands: &[FoldedCommand]) -> Option<(usize, u16)> {
+    let constant = |index: usize| match commands.get(index).map(|folded| &folded.command) {
+        Some(VmCommand::Push(Segment::Constant, value)) => Some(*value as i16),
+        _ => None,
+    };
+    let length = commands.len();
+    let op = match commands.last().map(|folded| &folded.command) {
+        Some(VmCommand::Arithmetic(op)) => *op,
+        _ => return None,
+    };
+    let (count, value) = match op {
+        ArithmeticOp::Neg | ArithmeticOp::Not => {
+            let y = constant(length.checked_sub(2)?)?;
+            let value = if op == ArithmeticOp::Neg { y.wrapping_neg() } else { !y };
+            (2, value)
+        },
+        _ => {
+            let x = constant(length.checked_sub(3)?)?;
+            let y = constant(length - 2)?;
+            let value = match op {
+                ArithmeticOp::Add => x.wrapping_add(y),
+                ArithmeticOp::Sub => x.wrapping_sub(y),
+                ArithmeticOp::And => x & y,
+                ArithmeticOp::Or => x | y,
+                // Both are non-negative, so the comparison is the same whatever the translation.
+                ArithmeticOp::Eq => -((x == y) as i16),
+                ArithmeticOp::Gt => -((x > y) as i16),
+                ArithmeticOp::Lt => -((x < y) as i16),
+                ArithmeticOp::Neg | ArithmeticOp::Not => unreachable!(),
+            };
+            (3, value)
+        },
+    };
+    if value >= 0 {
+        Some((count, value as u16))
+    } else {
+        None
+    }
+}
+
+// Removes instructions of translated code that cancel each other out, until nothing more can be removed.
+// Every pattern is made of consecutive instructions with no label in between, so none of them is a jump target
+// except the first one. The value left above the top of the stack is not kept, as nothing reads it.
+// Each line carries a tag, e.g. the command it was written for, which instructions put in its place inherit
+// from the first instruction they replace. Empty lines are dropped.
+pub fn peephole<T: Copy>(lines: &[(T, &str)]) -> Vec<(T, String)> {
+    let mut lines: Vec<(T, &str)> = lines.iter().map(|(tag, line)| (*tag, line.trim())).filter(|(_, line)| !line.is_empty()).collect();
+    loop {
+        let mut changed = false;
+        let mut index = 0;
+        while index < lines.len() {
+            if let Some((length, replacement)) = match_at(&lines, index) {
+                let tag = lines[index].0;
+                lines.splice(index..index + length, replacement.into_iter().map(|line| (tag, line)));
+                changed = true;
+            } else {
+                index += 1;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    lines.into_iter().map(|(tag, line)| (tag, String::from(line))).collect()
+}
+
+// The number of instructions to replace at `index`, and what to replace them with.
+fn match_at<'a, T>(lines: &[(T, &'a str)], index: usize) -> Option<(usize, Vec<&'a str>)> {
+    let rest = &lines[index..];
+    let line = |offset: usize| rest.get(offset).map(|(_, line)| *line);
+    let starts_with = |pattern: &[&str]| pattern.iter().enumerate().all(|(offset, expected)| line(offset) == Some(*expected));
+    // Whether the instruction after the first `length` ones loads A, so that the value left in A does not matter.
+    let reloads_a = |length: usize| line(length).is_none_or(|line| line.starts_with('@'));
+    // Pushing D then popping it into D: `push x` followed by `add`, `pop static i`, `if-goto` and so on.
+    if starts_with(&["@SP", "A=M", "M=D", "@SP", "M=M+1", "@SP", "AM=M-1", "D=M"]) && reloads_a(8) {
+        return Some((8, vec![]));
+    }
+    // Pushing then popping without using the value.
+    if starts_with(&["@SP", "M=M+1", "@SP", "AM=M-1"]) {
+        return Some((4, vec!["@SP", "A=M"]));
+    }
+    // Popping, writing the result where it was, then pushing it back: write it to the top of the stack instead.
+    if let Some(write) = line(2).filter(|write| write.starts_with("M=")) {
+        if starts_with(&["@SP", "AM=M-1", write, "@SP", "M=M+1"]) && reloads_a(5) {
+            return Some((5, vec!["@SP", "A=M-1", write]));
+        }
+    }
+    // Loading the same address twice, when the instruction in between does not change A.
+    if let (Some(load), Some(between)) = (line(0), line(1)) {
+        if load.starts_with('@') && line(2) == Some(load) && is_c_instruction_keeping_a(between) {
+            return Some((3, vec![load, between]));
+        }
+    }
+    None
+}
+
+fn is_c_instruction_keeping_a(line: &str) -> bool {
+    if line.starts_with('@') || line.starts_with('(') {
+        return false;
+    }
+    match line.split_once('=') {
+        Some((dest, _)) => !dest.contains('A'),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_constant(value: u16) -> VmCommand {
+        VmCommand::Push(Segment::Constant, value)
+    }
+
+    fn numbered(commands: Vec<VmCommand>) -> Vec<(usize, VmCommand)> {
+        commands.into_iter().enumerate().map(|(index, command)| (index + 1, command)).collect()
+    }
+
+    #[test]
+    fn fold_constants_folds_nested_arithmetic() {
+        let commands = numbered(vec![
+            push_constant(7),
+            push_constant(1),
+            push_constant(2),
+            VmCommand::Arithmetic(ArithmeticOp::Add),
+            VmCommand::Arithmetic(ArithmeticOp::Sub),
+            push_constant(3),
+            VmCommand::Arithmetic(ArithmeticOp::Lt),
+        ]);
+        let folded = fold_constants(&commands);
+        assert!(folded == vec![FoldedCommand { command: push_constant(0), sources: commands }]);
+    }
+
+    #[test]
+    fn fold_constants_keeps_results_push_constant_cannot_express() {
+        let commands = numbered(vec![
+            push_constant(1),
+            push_constant(2),
+            VmCommand::Arithmetic(ArithmeticOp::Sub),
+            push_constant(1),
+            VmCommand::Arithmetic(ArithmeticOp::Neg),
+            push_constant(32767),
+            push_constant(1),
+            VmCommand::Arithmetic(ArithmeticOp::Add),
+        ]);
+        let unfolded: Vec<VmCommand> = fold_constants(&commands).into_iter().map(|folded| folded.command).collect();
+        assert!(unfolded == commands.into_iter().map(|(_, command)| command).collect::<Vec<VmCommand>>());
+    }
+
+    // Runs the peephole optimizer on untagged code.
+    fn optimize(code: &str) -> String {
+        let lines: Vec<((), &str)> = code.lines().map(|line| ((), line)).collect();
+        let optimized: Vec<String> = peephole(&lines).into_iter().map(|(_, line)| line + "\n").collect();
+        optimized.concat()
+    }
+
+    #[test]
+    fn peephole_removes_push_followed_by_pop() {
+        let code = "@5\nD=A\n@SP\nA=M\nM=D\n@SP\nM=M+1\n@SP\nAM=M-1\nD=M\n@SP\nAM=M-1\nM=D+M\n@SP\nM=M+1\n";
+        assert_eq!(optimize(code), "@5\nD=A\n@SP\nA=M-1\nM=D+M\n");
+    }
+
+    #[test]
+    fn peephole_keeps_a_when_it_is_used_after_a_label() {
+        let code = "@SP\nAM=M-1\nM=D+M\n@SP\nM=M+1\n(LOOP)\nD=M\n";
+        assert_eq!(optimize(code), code);
+    }
+}
@@ -1,27 +1,188 @@
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Segment {
+    Local,
+    Argument,
+    This,
+    That,
+    Pointer,
+    Temp,
+    Constant,
+    Static,
+}
 
-pub enum CommandType {
-    C_ARITHMETIC,
-    C_PUSH,
-    C_POP,
-    C_LABEL,
-    C_GOTO,
-    C_IF,
-    C_FUNCTION,
-    C_RETURN,
-    C_CALL,
+impl Segment {
+    fn from_name(name: &str) -> Option<Segment> {
+        match name {
+            "local" => Some(Segment::Local),
+            "argument" => Some(Segment::Argument),
+            "this" => Some(Segment::This),
+            "that" => Some(Segment::That),
+            "pointer" => Some(Segment::Pointer),
+            "temp" => Some(Segment::Temp),
+            "constant" => Some(Segment::Constant),
+            "static" => Some(Segment::Static),
+            _ => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Segment::Local => "local",
+            Segment::Argument => "argument",
+            Segment::This => "this",
+            Segment::That => "that",
+            Segment::Pointer => "pointer",
+            Segment::Temp => "temp",
+            Segment::Constant => "constant",
+            Segment::Static => "static",
+        }
+    }
+    // The largest index the segment accepts.
+    // Temp is RAM[5..=12] and pointer is THIS and THAT. Any other index is written in an A-instruction,
+    // or in the name of a static variable, so it must fit in the 15 bits of one.
+    pub fn max_index(&self) -> u16 {
+        match self {
+            Segment::Temp => 7,
+            Segment::Pointer => 1,
+            _ => MAX_CONSTANT,
+        }
+    }
+}
+
+// The largest value an A-instruction can hold.
+pub const MAX_CONSTANT: u16 = 32767;
+
+// `call` writes `@5+num_args`, which must fit in an A-instruction.
+pub const MAX_ARGUMENTS: u16 = MAX_CONSTANT - 5;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ArithmeticOp {
+    Add,
+    Sub,
+    Neg,
+    Eq,
+    Gt,
+    Lt,
+    And,
+    Or,
+    Not,
+}
+
+impl ArithmeticOp {
+    fn from_name(name: &str) -> Option<ArithmeticOp> {
+        match name {
+            "add" => Some(ArithmeticOp::Add),
+            "sub" => Some(ArithmeticOp::Sub),
+            "neg" => Some(ArithmeticOp::Neg),
+            "eq" => Some(ArithmeticOp::Eq),
+            "gt" => Some(ArithmeticOp::Gt),
+            "lt" => Some(ArithmeticOp::Lt),
+            "and" => Some(ArithmeticOp::And),
+            "or" => Some(ArithmeticOp::Or),
+            "not" => Some(ArithmeticOp::Not),
+            _ => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            ArithmeticOp::Add => "add",
+            ArithmeticOp::Sub => "sub",
+            ArithmeticOp::Neg => "neg",
+            ArithmeticOp::Eq => "eq",
+            ArithmeticOp::Gt => "gt",
+            ArithmeticOp::Lt => "lt",
+            ArithmeticOp::And => "and",
+            ArithmeticOp::Or => "or",
+            ArithmeticOp::Not => "not",
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum VmCommand {
+    Arithmetic(ArithmeticOp),
+    Push(Segment, u16),
+    Pop(Segment, u16),
+    Label(String),
+    Goto(String),
+    If(String),
+    Function(String, u16),
+    Call(String, u16),
+    Return,
+}
+
+// Writes the command back as VM code.
+impl std::fmt::Display for VmCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            VmCommand::Arithmetic(op) => write!(f, "{}", op.name()),
+            VmCommand::Push(segment, index) => write!(f, "push {} {}", segment.name(), index),
+            VmCommand::Pop(segment, index) => write!(f, "pop {} {}", segment.name(), index),
+            VmCommand::Label(label) => write!(f, "label {}", label),
+            VmCommand::Goto(label) => write!(f, "goto {}", label),
+            VmCommand::If(label) => write!(f, "if-goto {}", label),
+            VmCommand::Function(function_name, num_locals) => write!(f, "function {} {}", function_name, num_locals),
+            VmCommand::Call(function_name, num_args) => write!(f, "call {} {}", function_name, num_args),
+            VmCommand::Return => write!(f, "return"),
+        }
+    }
+}
+
+// The commands of a .vm file, with their line numbers.
+pub struct VmFile {
+    pub path: String,
+    pub commands: Vec<(usize, VmCommand)>,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum ParseError {
+    UnknownCommand(String),
+    UnknownSegment(String),
+    InvalidIndex(String),
+    NegativeIndex(String),
+    IndexOutOfRange(Segment, u16),
+    PopConstant,
+    WrongArgumentCount(String),
+    TooManyArguments(u16),
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParseError::UnknownCommand(command) => write!(f, "unknown command `{}`", command),
+            ParseError::UnknownSegment(segment) => write!(f, "unknown segment `{}`", segment),
+            ParseError::InvalidIndex(index) => write!(f, "`{}` is not a valid index", index),
+            ParseError::NegativeIndex(index) => write!(f, "index `{}` must not be negative", index),
+            ParseError::IndexOutOfRange(segment, index) => {
+                write!(f, "index {} is out of range for segment `{}`, which accepts 0..={}", index, segment.name(), segment.max_index())
+            },
+            ParseError::PopConstant => write!(f, "cannot pop to segment `constant`, as it is not stored in memory"),
+            ParseError::WrongArgumentCount(command) => write!(f, "wrong number of arguments for `{}`", command),
+            ParseError::TooManyArguments(num_args) => write!(f, "a call takes at most {} arguments, not {}", MAX_ARGUMENTS, num_args),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseDiagnostic {
+    // 1-based line number in the .vm file.
+    pub line: usize,
+    pub error: ParseError,
 }
 
 pub struct Parser {
     lines: Vec<String>,
+    // 1-based line numbers of `lines` in the original file.
+    line_numbers: Vec<usize>,
     current_index: usize,
 }
 
 impl Parser {
     pub fn create(contents: String) -> Parser {
-        let mut lines = contents.split("\n").map(|s| s.to_string()).collect();
-        lines = Self::get_codes_comment_removed(&lines);
+        let lines: Vec<String> = contents.split('\n').map(|s| s.to_string()).collect();
+        let (lines, line_numbers) = Self::get_codes_comment_removed(&lines);
         Parser {
             lines,
+            line_numbers,
             current_index: 0,
         }
     }
@@ -31,40 +192,161 @@ impl Parser {
     pub fn advance(&mut self) {
         self.current_index += 1;
     }
-    pub fn commandType(&self) -> CommandType {
-        let current_line = self.lines.get(self.current_index).unwrap();
-        let first_word = current_line.split(' ').nth(0).unwrap();
-        match first_word {
-            "add" | "sub" | "neg" | "eq" | "gt" | "lt" | "and" | "or" | "not" => CommandType::C_ARITHMETIC,
-            "push" => CommandType::C_PUSH,
-            "pop" => CommandType::C_POP,
-            _ => panic!("invalid command type !"),
-        }
-    }
-    pub fn arg0(&self) -> String {
-        let current_line = self.lines.get(self.current_index).unwrap();
-        let first_word = current_line.split(' ').nth(0).unwrap();
-        String::from(first_word)
+    pub fn getCurrentLineNumber(&self) -> usize {
+        *self.line_numbers.get(self.current_index).unwrap()
     }
-    pub fn arg1(&self) -> String {
+    pub fn command(&self) -> Result<VmCommand, ParseError> {
         let current_line = self.lines.get(self.current_index).unwrap();
-        let second_word = current_line.split(' ').nth(1).unwrap();
-        String::from(second_word)
+        let words: Vec<&str> = current_line.split_whitespace().collect();
+        let first_word = words[0];
+        let argument_count = match first_word {
+            "return" => 0,
+            "label" | "goto" | "if-goto" => 1,
+            "push" | "pop" | "function" | "call" => 2,
+            _ if ArithmeticOp::from_name(first_word).is_some() => 0,
+            _ => return Err(ParseError::UnknownCommand(String::from(first_word))),
+        };
+        if words.len() != argument_count + 1 {
+            return Err(ParseError::WrongArgumentCount(String::from(first_word)));
+        }
+        let index = || match words[2].parse::<i32>() {
+            Ok(index) if index < 0 => Err(ParseError::NegativeIndex(String::from(words[2]))),
+            _ => words[2].parse::<u16>().map_err(|_| ParseError::InvalidIndex(String::from(words[2]))),
+        };
+        // The segment and its index, rejecting indices the segment does not have.
+        let segment_index = || {
+            let segment = Segment::from_name(words[1]).ok_or(ParseError::UnknownSegment(String::from(words[1])))?;
+            let index = index()?;
+            if index > segment.max_index() {
+                Err(ParseError::IndexOutOfRange(segment, index))
+            } else {
+                Ok((segment, index))
+            }
+        };
+        let command = match first_word {
+            "push" => {
+                let (segment, index) = segment_index()?;
+                VmCommand::Push(segment, index)
+            },
+            "pop" => {
+                let (segment, index) = segment_index()?;
+                if segment == Segment::Constant {
+                    return Err(ParseError::PopConstant);
+                }
+                VmCommand::Pop(segment, index)
+            },
+            "label" => VmCommand::Label(String::from(words[1])),
+            "goto" => VmCommand::Goto(String::from(words[1])),
+            "if-goto" => VmCommand::If(String::from(words[1])),
+            "function" => VmCommand::Function(String::from(words[1]), index()?),
+            "call" => {
+                let num_args = index()?;
+                if num_args > MAX_ARGUMENTS {
+                    return Err(ParseError::TooManyArguments(num_args));
+                }
+                VmCommand::Call(String::from(words[1]), num_args)
+            },
+            "return" => VmCommand::Return,
+            _ => VmCommand::Arithmetic(ArithmeticOp::from_name(first_word).unwrap()),
+        };
+        Ok(command)
     }
-    pub fn arg2(&self) -> i32 {
-        let current_line = self.lines.get(self.current_index).unwrap();
-        let third_word = current_line.split(' ').nth(2).unwrap();
-        third_word.parse::<i32>().unwrap()
+    // Parses every command at once, with the line number of each, or every error found along the way.
+    pub fn parse(contents: String) -> Result<Vec<(usize, VmCommand)>, Vec<ParseDiagnostic>> {
+        let mut parser = Self::create(contents);
+        let mut commands = vec![];
+        let mut errors = vec![];
+        while parser.hasMoreCommands() {
+            match parser.command() {
+                Ok(command) => commands.push((parser.getCurrentLineNumber(), command)),
+                Err(error) => errors.push(ParseDiagnostic {
+                    line: parser.getCurrentLineNumber(),
+                    error,
+                }),
+            }
+            parser.advance();
+        }
+        if errors.is_empty() {
+            Ok(commands)
+        } else {
+            Err(errors)
+        }
     }
-    fn get_codes_comment_removed(lines: &Vec<String>) -> Vec<String> {
+    fn get_codes_comment_removed(lines: &[String]) -> (Vec<String>, Vec<usize>) {
         let mut vm_codes = vec![];
-        for line in lines {
-            let first_split = line.split("//").nth(0).unwrap();
+        let mut line_numbers = vec![];
+        for (index, line) in lines.iter().enumerate() {
+            let first_split = line.split("//").next().unwrap();
             let trimmed_line = first_split.trim();
-            if trimmed_line.len() > 0 {
+            if !trimmed_line.is_empty() {
                 vm_codes.push(String::from(trimmed_line));
+                line_numbers.push(index + 1);
             }
         }
-        vm_codes
+        (vm_codes, line_numbers)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The line and error of every command that does not parse.
+    fn errors(contents: &str) -> Vec<(usize, ParseError)> {
+        match Parser::parse(String::from(contents)) {
+            Ok(_) => vec![],
+            Err(errors) => errors.into_iter().map(|diagnostic| (diagnostic.line, diagnostic.error)).collect(),
+        }
+    }
+
+    #[test]
+    fn indices_are_checked_against_their_segment() {
+        let contents = "push temp 7\npush temp 8\npop pointer 2\npush constant 32767\npush constant 32768\npush constant -1\npop constant 0\n";
+        assert_eq!(errors(contents), vec![
+            (2, ParseError::IndexOutOfRange(Segment::Temp, 8)),
+            (3, ParseError::IndexOutOfRange(Segment::Pointer, 2)),
+            (5, ParseError::IndexOutOfRange(Segment::Constant, 32768)),
+            (6, ParseError::NegativeIndex(String::from("-1"))),
+            (7, ParseError::PopConstant),
+        ]);
+    }
+
+    #[test]
+    fn indices_written_in_a_instructions_fit_in_15_bits() {
+        let contents = "push local 32767\npush local 40000\npop argument 32768\n\npush static 40000\ncall f 32762\ncall f 32765\npush this 70000\n";
+        assert_eq!(errors(contents), vec![
+            (2, ParseError::IndexOutOfRange(Segment::Local, 40000)),
+            (3, ParseError::IndexOutOfRange(Segment::Argument, 32768)),
+            (5, ParseError::IndexOutOfRange(Segment::Static, 40000)),
+            (7, ParseError::TooManyArguments(32765)),
+            (8, ParseError::InvalidIndex(String::from("70000"))),
+        ]);
+    }
+
+    #[test]
+    fn errors_are_collected_with_their_line_numbers() {
+        let contents = "// Bad.vm\n\npush local 0 // fine\nfoo\npush heap 1\n  pop\nlabel\nreturn 1\nadd 2\npush local x\nadd\n";
+        assert_eq!(errors(contents), vec![
+            (4, ParseError::UnknownCommand(String::from("foo"))),
+            (5, ParseError::UnknownSegment(String::from("heap"))),
+            (6, ParseError::WrongArgumentCount(String::from("pop"))),
+            (7, ParseError::WrongArgumentCount(String::from("label"))),
+            (8, ParseError::WrongArgumentCount(String::from("return"))),
+            (9, ParseError::WrongArgumentCount(String::from("add"))),
+            (10, ParseError::InvalidIndex(String::from("x"))),
+        ]);
+    }
+
+    #[test]
+    fn commands_are_written_back_as_vm_code() {
+        let lines = [
+            "push constant 7", "pop local 0", "push argument 1", "pop this 2", "push that 3", "pop pointer 1",
+            "push temp 6", "pop static 4", "add", "sub", "neg", "eq", "gt", "lt", "and", "or", "not",
+            "label LOOP", "goto LOOP", "if-goto Main.main$END", "function Main.main 2", "call Math.multiply 2", "return",
+        ];
+        let commands = Parser::parse(format!("// Every command\n{}\n", lines.join("\n"))).unwrap();
+        let written: Vec<(usize, String)> = commands.iter().map(|(line, command)| (*line, command.to_string())).collect();
+        let expected: Vec<(usize, String)> = lines.iter().enumerate().map(|(index, line)| (index + 2, String::from(*line))).collect();
+        assert_eq!(written, expected);
+    }
+}
@@ -0,0 +1,55 @@
+use super::code_writer;
+use super::parser;
+
+// The Assembler allocates variables from RAM[16], and the stack begins at RAM[256].
+pub const STATIC_BASE: usize = 16;
+pub const STACK_BASE: usize = 256;
+
+// A static variable, `File.i` in the translated code, with its address.
+pub struct StaticVariable {
+    pub symbol: String,
+    pub path: String,
+    // The line where the variable first appears.
+    pub line: usize,
+    pub index: u16,
+    pub address: usize,
+}
+
+// Allocates static variables from RAM[16] in order of first appearance.
+pub fn allocate(vm_files: &[parser::VmFile]) -> Vec<StaticVariable> {
+    let mut statics: Vec<StaticVariable> = vec![];
+    for vm_file in vm_files {
+        let filename_vm = code_writer::CodeWriter::get_vm_filename_from_full_path(&vm_file.path);
+        for (line, command) in &vm_file.commands {
+            if let parser::VmCommand::Push(parser::Segment::Static, index) | parser::VmCommand::Pop(parser::Segment::Static, index) = command {
+                let symbol = format!("{}.{}", filename_vm, index);
+                if statics.iter().all(|variable| variable.symbol != symbol) {
+                    statics.push(StaticVariable {
+                        address: STATIC_BASE + statics.len(),
+                        symbol,
+                        path: vm_file.path.clone(),
+                        line: *line,
+                        index: *index,
+                    });
+                }
+            }
+        }
+    }
+    statics
+}
+
+// Same as allocate, with the addresses the Assembler gave them in the symbol table of the translated code.
+// The Assembler allocates variables from RAM[16] in order of first appearance, but statics are not the only ones:
+// a call to a function that is not defined, e.g. of a library that is not translated, also makes its name a variable.
+pub fn allocate_as_assembled(vm_files: &[parser::VmFile], symbol_table: &assembler::symbol_table::SymbolTable) -> Vec<StaticVariable> {
+    let mut statics = allocate(vm_files);
+    for variable in &mut statics {
+        variable.address = symbol_table.GetAddress(&variable.symbol);
+    }
+    statics
+}
+
+// The first static variable that does not fit below the stack, if any.
+pub fn spilled(statics: &[StaticVariable]) -> Option<&StaticVariable> {
+    statics.iter().find(|variable| variable.address >= STACK_BASE)
+}